@@ -1,21 +1,30 @@
 extern crate tcod;
 
+use std::cell::RefCell;
 use std::cmp::*;
-use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::fmt;
+use std::time::Instant;
+use std::ops::{Index, IndexMut};
+use rand::{Rng, SeedableRng, StdRng};
 
 use tcod::console::*;
 use tcod::colors::{self,Color};
 use tcod::input::Key;
 use tcod::input::KeyCode::*;
 use tcod::input::{self, Event, Mouse};
-use tcod::map::{Map as FovMap, FovAlgorithm};
+use tcod::map::{Map as TcodMap, FovAlgorithm};
 
 const SCREEN_WIDTH: i32 = 80;
 const SCREEN_HEIGHT: i32 = 50;
 const LIMIT_FPS: i32 = 40;
 
-const MAP_WIDTH: i32 = 80;
-const MAP_HEIGHT: i32 = 43;
+// The map can be larger than the part of it shown on screen by the camera.
+const MAP_WIDTH: i32 = 120;
+const MAP_HEIGHT: i32 = 80;
+const CAMERA_WIDTH: i32 = 80;
+const CAMERA_HEIGHT: i32 = 43;
 
 // Panel constants.
 const BAR_WIDTH: i32 = 20;
@@ -25,51 +34,280 @@ const MSG_X: i32 = BAR_WIDTH + 2;
 const MSG_WIDTH: i32 = SCREEN_WIDTH - MSG_X;
 const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
 const INVENTORY_WIDTH: i32 = 50;
+const CHARACTER_SCREEN_WIDTH: i32 = 30;
+const MSG_HISTORY_SIZE: usize = 1000;
+
+// Event constants.
+const SLAYER_KILLS: u32 = 25;
+const HOARDER_PICK_UPS: u32 = 20;
+const DEEP_DELVER_LEVEL: u32 = 5;
+
+// Minimap constants: each cell of the minimap shows a square of tiles.
+const MINIMAP_SCALE: i32 = 4;
+const MINIMAP_WIDTH: i32 = (MAP_WIDTH + MINIMAP_SCALE - 1) / MINIMAP_SCALE;
+const MINIMAP_HEIGHT: i32 = (MAP_HEIGHT + MINIMAP_SCALE - 1) / MINIMAP_SCALE;
 
 const ROOM_MAX_SIZE: i32 = 10;
 const ROOM_MIN_SIZE: i32 = 6;
-const MAX_ROOMS: i32 = 30;
+const MAX_ROOMS: i32 = 80;
 const MAX_ROOM_MONSTERS: i32 = 3;
 const MAX_ROOM_ITEMS: i32 = 2;
+const MAX_ROOM_TRAPS: i32 = 1;
+
+// BSP generation constants.
+const BSP_MIN_LEAF_SIZE: i32 = 10;
+const BSP_MIN_ROOM_SIZE: i32 = 5;
+// Extra connections between neighbouring rooms, so that the dungeon has loops.
+const BSP_EXTRA_LOOPS: i32 = 3;
+
+// Vault constants: each room placed has a chance to come with a vault (rooms maps), or to be
+// replaced by one (BSP maps).
+const MAX_VAULTS: usize = 2;
+const VAULT_CHANCE: f32 = 0.08;
+const VAULT_PLACEMENT_TRIES: i32 = 50;
+
+/// Handcrafted rooms, stamped into the map where they fit. Legend:
+/// `#` wall, `.` floor, `+` door, `o` orc, `M` random monster, `!` potion, `?` random item,
+/// `>` stairs, and spaces are left untouched. Doors on the edge of a vault are connected to the
+/// rest of the map.
+/// The templates are read from the `*.txt` files in VAULT_DIR, and these built-in copies are
+/// only used when none can be read.
+const VAULT_DIR: &str = "vaults";
+const VAULTS: &[&str] = &[
+    include_str!("../vaults/treasure.txt"),
+    include_str!("../vaults/orc_den.txt"),
+    include_str!("../vaults/shrine.txt"),
+];
+
+// Cave generation constants.
+const CAVE_WALL_CHANCE: f32 = 0.45;
+const CAVE_SMOOTHING_STEPS: i32 = 5;
+const CAVE_MIN_FLOOR_RATIO: f32 = 0.35;
+const CAVE_TILES_PER_MONSTER: usize = 100;
+const CAVE_TILES_PER_ITEM: usize = 150;
+const CAVE_TILES_PER_TRAP: usize = 300;
+// Monsters are not placed closer than this to the player's starting position.
+const CAVE_SAFE_DISTANCE: i32 = 8;
+// Caves which are too cramped are grown again, at most this many times in a row.
+const MAX_CAVE_ATTEMPTS: u32 = 20;
+
+// Levels which fail validation are generated again, at most this many times in a row.
+const MAX_MAP_ATTEMPTS: u32 = 100;
+
+const PLAYER_ID: Entity = Entity { index: 0, generation: 0 };
 
-const PLAYER_ID: usize = 0;
+// Time constants: actors gain their speed in energy every turn, and can act whenever they have
+// accumulated enough energy. Each action then costs some energy.
+const ENERGY_TO_ACT: i32 = 100;
+const NORMAL_SPEED: i32 = 100;
+const MOVE_COST: i32 = 100;
+const ATTACK_COST: i32 = 100;
+const PICK_UP_COST: i32 = 50;
+const USE_ITEM_COST: i32 = 100;
+const WAIT_COST: i32 = 100;
+const FIRE_COST: i32 = 100;
+const THROW_COST: i32 = 100;
+const OPEN_DOOR_COST: i32 = 100;
+const CLOSE_DOOR_COST: i32 = 100;
+const SEARCH_COST: i32 = 100;
+const TORCH_COST: i32 = 100;
 
 const COLOR_DARK_WALL: Color = Color { r: 0, g: 0, b: 100 };
 const COLOR_LIGHT_WALL: Color = Color { r: 130, g: 110, b: 50 };
 const COLOR_DARK_GROUND: Color = Color { r: 50, g: 50, b: 150 };
 const COLOR_LIGHT_GROUND: Color = Color { r: 200, g: 180, b: 50 };
+const COLOR_DARK_DOOR: Color = Color { r: 90, g: 70, b: 40 };
+const COLOR_LIGHT_DOOR: Color = Color { r: 140, g: 80, b: 20 };
 const COLOR_ORC: Color = colors::DESATURATED_GREEN;
 const COLOR_TROLL: Color = colors::DARKER_GREEN;
+const COLOR_BAT: Color = colors::LIGHT_SEPIA;
+const COLOR_ZOMBIE: Color = colors::DARK_SEPIA;
+const COLOR_FIRE_IMP: Color = colors::FLAME;
+const COLOR_ICE_GOLEM: Color = colors::LIGHT_CYAN;
+const COLOR_ORC_WARLORD: Color = colors::DARK_GREEN;
+const COLOR_TARGET: Color = colors::LIGHT_RED;
+const COLOR_LINE_OF_FIRE: Color = colors::DARK_RED;
+const COLOR_PLAYER_LIGHT: Color = Color { r: 255, g: 230, b: 180 };
+const COLOR_TORCH_LIGHT: Color = Color { r: 255, g: 170, b: 70 };
+const COLOR_FUNGUS_LIGHT: Color = Color { r: 70, g: 230, b: 130 };
+const COLOR_FIRE_LIGHT: Color = Color { r: 255, g: 110, b: 30 };
+const COLOR_HIT_FLASH: Color = Color { r: 160, g: 0, b: 0 };
 
 const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;
 const FOV_LIGHT_WALLS: bool = true;
+const SIGHT_RADIUS: i32 = 30;
 const TORCH_RADIUS: i32 = 10;
+// The player's torch. Putting it out hides them from monsters which cannot see in the dark.
+const PLAYER_TORCH: Light = Light { radius: TORCH_RADIUS, color: COLOR_PLAYER_LIGHT, falloff: 1.0 };
+
+// Lighting constants: tiles in the field of view are only visible if their brightest colour
+// channel gets at least this fraction of full light.
+const MIN_VISIBLE_LIGHT: f32 = 0.15;
+const LIT_ROOM_CHANCE: f32 = 0.4;
+const TORCHES_PER_LIT_ROOM: i32 = 2;
+const FIRE_CHANCE: f32 = 0.1;
+const CAVE_TILES_PER_FUNGUS: usize = 150;
+
+// Status effect constants.
+const POISON_DAMAGE: i32 = 1;
+const REGENERATION_AMOUNT: i32 = 1;
+const BLIND_SIGHT_RADIUS: i32 = 1;
+
+// Item constants.
+const HEAL_AMOUNT: i32 = 10;
+const HASTE_DURATION: i32 = 20;
+const REGENERATION_DURATION: i32 = 20;
+const CONFUSE_RANGE: i32 = 8;
+const CONFUSE_DURATION: i32 = 10;
+const FIREBALL_RANGE: i32 = 8;
+const FIREBALL_RADIUS: f32 = 2.5;
+const FIREBALL_DAMAGE: Dice = Dice { count: 3, sides: 6, bonus: 0 };
+const LIGHTNING_RANGE: i32 = 5;
+const LIGHTNING_DAMAGE: Dice = Dice { count: 2, sides: 8, bonus: 4 };
+const SHORT_BOW: Item =
+    Item::Launcher(Launcher { ammo: AmmoKind::Arrow, damage: Dice { count: 1, sides: 6, bonus: 1 }, range: 8 });
+const CROSSBOW: Item =
+    Item::Launcher(Launcher { ammo: AmmoKind::Bolt, damage: Dice { count: 1, sides: 8, bonus: 2 }, range: 10 });
+
+// Loot constants: monsters drop items more often on deeper levels, and a boss guards the stairs
+// every few levels.
+const LOOT_CHANCE_PER_LEVEL: f32 = 0.03;
+const BOSS_LEVEL_INTERVAL: u32 = 4;
+
+// Combat constants: attacks hit on a percentile roll, whose odds go up with the attacker's
+// accuracy and down with the target's evasion. The lowest rolls are critical hits.
+const BASE_HIT_CHANCE: i32 = 75;
+const HIT_CHANCE_PER_POINT: i32 = 5;
+const MIN_HIT_CHANCE: i32 = 5;
+const MAX_HIT_CHANCE: i32 = 95;
+const CRITICAL_CHANCE: i32 = 5;
+// Damage dealt by a hit, however thick the target's armour.
+const MIN_DAMAGE: i32 = 1;
+
+// Ranged combat constants.
+const THROW_RANGE: i32 = 6;
+const SHATTER_RADIUS: f32 = 1.5;
+
+// Search constants: actively searching reveals secret doors and traps next to the player, while
+// passive perception may reveal traps a bit further away every turn.
+const SEARCH_RADIUS: i32 = 1;
+const SEARCH_CHANCE: f32 = 0.5;
+const PERCEPTION_RADIUS: i32 = 3;
+const PERCEPTION_CHANCE: f32 = 0.1;
+
+// Trap constants.
+const DART_DAMAGE: i32 = 3;
+const PIT_DAMAGE: i32 = 2;
+const ALARM_RADIUS: f32 = 15.0;
+const POISON_GAS_RADIUS: f32 = 1.5;
+const POISON_GAS_DURATION: i32 = 6;
+const FLASH_RADIUS: f32 = 2.5;
+const FLASH_DURATION: i32 = 5;
+
+// Noise constants: how far noises carry, in tiles. Every wall or closed door in the way muffles
+// them as much as a few more tiles, and sleeping monsters only hear them from closer.
+const NOISE_FIGHTING: f32 = 8.0;
+const NOISE_DOOR: f32 = 6.0;
+const NOISE_FOOTSTEPS: f32 = 3.0;
+const NOISE_WALL_PENALTY: f32 = 4.0;
+const ASLEEP_HEARING: f32 = 0.5;
+const ASLEEP_CHANCE: f32 = 0.5;
+// Chance for a wandering monster to take a step every turn.
+const WANDER_CHANCE: f32 = 0.3;
+
+// Monsters do not bother looking for paths longer than this.
+const MAX_PATH_LENGTH: i32 = 30;
 
 
 // Common functions
 
-/// Mutably borrow two different elements from the given slice.
-/// Panics if the two indices have the same value, or if they are out of bounds.
-pub fn mut_two<T>(items: &mut [T], i: usize, j: usize) -> (&mut T, &mut T) {
-    assert!(i != j, "The two indices should be different");
-    let (left, right) = items.split_at_mut(std::cmp::max(i, j));
-    if i < j {
-        (&mut left[i], &mut right[0])
-    } else {
-        (&mut right[0], &mut left[j])
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::new().expect("Could not seed the random number generator"));
+}
+
+/// Handle to the game's random number generator. All the randomness of the game goes through it,
+/// so that seeding it generates the same levels again.
+pub struct GameRng;
+
+impl Rng for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        RNG.with(|rng| rng.borrow_mut().next_u32())
+    }
+}
+
+pub fn rng() -> GameRng {
+    GameRng
+}
+
+pub fn seed_rng(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::from_seed(&[seed as usize]));
+}
+
+/// Tiles on the straight line between two points (Bresenham's algorithm), excluding the first
+/// point and including the last one.
+pub fn line(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+    let (mut x, mut y) = from;
+    let dx = (to.0 - x).abs();
+    let dy = -(to.1 - y).abs();
+    let step_x = if x < to.0 { 1 } else { -1 };
+    let step_y = if y < to.1 { 1 } else { -1 };
+    let mut error = dx + dy;
+
+    let mut points = vec![];
+    while (x, y) != to {
+        let error2 = 2 * error;
+        if error2 >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if error2 <= dx {
+            error += dx;
+            y += step_y;
+        }
+        points.push((x, y));
     }
+    points
 }
 
 
+
 // Specific code
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 struct Fighter {
     max_hp: i32,
     hp: i32,
-    defence: i32,
-    power: i32,
+    /// Bonus to hit with attacks.
+    accuracy: i32,
+    /// Penalty to be hit by attacks.
+    evasion: i32,
+    damage: Dice,
+    damage_type: DamageType,
+    /// Damage taken off every hit.
+    armour: i32,
+    resistances: Resistances,
+    /// Armour worn on top of the fighter's own.
+    worn: Option<ArmourKind>,
     on_death: DeathCallback,
+    /// Effect applied to the target of a successful attack.
+    attack_effect: Option<Effect>,
+}
+
+impl Fighter {
+    pub fn total_armour(&self) -> i32 {
+        self.armour + self.worn.map_or(0, ArmourKind::armour)
+    }
+
+    /// How the fighter takes a type of damage, worn armour included.
+    pub fn affinity(&self, kind: DamageType) -> Affinity {
+        let worn = self.worn.map_or(Affinity::Normal, |armour| armour.resistances().get(kind));
+        self.resistances.get(kind).combine(worn)
+    }
+
+    /// Damage actually taken from a hit of the given type, once affinities are applied.
+    pub fn damage_from(&self, damage: i32, kind: DamageType) -> i32 {
+        self.affinity(kind).apply(damage)
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -79,41 +317,274 @@ enum DeathCallback {
 }
 
 impl DeathCallback {
-    fn callback(self, object: &mut Object, messages: &mut Messages) {
+    fn callback(self, id: Entity, map: &Map, world: &mut World, messages: &mut Messages) {
         use self::DeathCallback::*;
-        let callback: fn(&mut Object, &mut Messages) = match self {
+        let callback: fn(Entity, &Map, &mut World, &mut Messages) = match self {
             Player => player_death,
             Monster => monster_death,
         };
-        callback(object, messages);
+        callback(id, map, world, messages);
     }
 }
 
-fn player_death(player: &mut Object, messages: &mut Messages) {
+fn player_death(player: Entity, _map: &Map, world: &mut World, messages: &mut Messages) {
     // The game ends!
-    log_message(messages, "You died!", colors::DARK_RED);
+    let name = world.names[player].clone();
+    world.emit(GameEvent::Died { id: player, name }, messages);
 
     // Transform the player into a corpse
-    player.alive = false;
-    player.char = '%';
-    player.color = colors::DARK_RED;
-    player.fighter = None;
+    world.renderables[player].char = '%';
+    world.renderables[player].color = colors::DARK_RED;
+    world.fighters.remove(player);
+    world.effects.remove(player);
 }
 
-fn monster_death(monster: &mut Object, messages: &mut Messages) {
+fn monster_death(monster: Entity, map: &Map, world: &mut World, messages: &mut Messages) {
+    let name = world.names[monster].clone();
+    world.emit(GameEvent::Died { id: monster, name }, messages);
+    drop_loot(monster, map, world, messages);
+
     // Transform into a traversable, unattackable, immobile corpse
-    log_message(messages, format!("{} is dead!", monster.name), colors::ORANGE);
-    monster.char = '%';
-    monster.color = colors::DARK_RED;
-    monster.traversable = true;
-    monster.fighter = None;
-    monster.ai = None;
-    monster.name = format!("remains of {}", monster.name);
+    world.renderables[monster].char = '%';
+    world.renderables[monster].color = colors::DARK_RED;
+    world.blocks.remove(monster);
+    world.fighters.remove(monster);
+    world.ais.remove(monster);
+    world.effects.remove(monster);
+    world.names[monster] = format!("remains of {}", world.names[monster]);
+}
+
+/// An item a monster may drop, from the given dungeon level on.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct LootEntry {
+    item: Item,
+    weight: u32,
+    min_level: u32,
+}
+
+/// What a monster drops when it dies.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct LootTable {
+    /// Chance to drop one of the entries on the first level, going up by LOOT_CHANCE_PER_LEVEL
+    /// on every deeper one.
+    drop_chance: f32,
+    entries: &'static [LootEntry],
+    gold_chance: f32,
+    /// Gold pieces dropped, multiplied by the dungeon level.
+    gold: Dice,
+    /// Items dropped every time, such as a boss's treasure.
+    guaranteed: &'static [Item],
+}
+
+impl LootTable {
+    /// Roll the items dropped on the given dungeon level.
+    pub fn roll(&self, level: u32) -> Vec<Object> {
+        let mut drops: Vec<Object> = self.guaranteed.iter().map(|&item| make_item(item, 0, 0)).collect();
+
+        let chance = self.drop_chance + LOOT_CHANCE_PER_LEVEL * level.saturating_sub(1) as f32;
+        let entries: Vec<&LootEntry> = self.entries.iter().filter(|entry| entry.min_level <= level).collect();
+        let total_weight: u32 = entries.iter().map(|entry| entry.weight).sum();
+        if total_weight > 0 && rng().gen::<f32>() < chance {
+            let mut roll = rng().gen_range(0, total_weight);
+            for entry in entries {
+                if roll < entry.weight {
+                    drops.push(make_item(entry.item, 0, 0));
+                    break;
+                }
+                roll -= entry.weight;
+            }
+        }
+
+        if rng().gen::<f32>() < self.gold_chance {
+            let mut gold = make_item(Item::Gold, 0, 0);
+            gold.count = max(self.gold.roll(), 1) * level as i32;
+            drops.push(gold);
+        }
+        drops
+    }
+}
+
+/// Spawn what a dying monster drops: the first item falls on its tile, and the others on the free
+/// floor around it.
+fn drop_loot(monster: Entity, map: &Map, world: &mut World, messages: &mut Messages) {
+    let drops = match world.loot.remove(monster) {
+        Some(loot) => loot.roll(world.depth),
+        None => return,
+    };
+    if drops.is_empty() {
+        return;
+    }
+
+    let (x, y) = world.pos(monster);
+    let mut spots = vec![(x, y)];
+    spots.extend(map.neighbours(x, y).filter(|&(x, y)| {
+        map[(x, y)].traversable && world.blocking_at(x, y).is_none() && world.items_at(x, y).next().is_none()
+    }));
+    let items = drops.iter().map(Object::display_name).collect();
+    for (index, mut drop) in drops.into_iter().enumerate() {
+        let (x, y) = spots[index % spots.len()];
+        drop.set_pos(x, y);
+        world.spawn(drop);
+    }
+    let name = world.names[monster].clone();
+    world.emit(GameEvent::LootDropped { id: monster, name, items }, messages);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Ai {
+    alertness: Alertness,
+    /// Where the monster heads to when it cannot see the player: where it last saw them, or a
+    /// noise it heard.
+    destination: Option<(i32, i32)>,
+    /// Noise heard since the monster last acted, and how alert it was before.
+    heard: Option<(Noise, Alertness)>,
+    sight_radius: i32,
+    /// Whether the monster can see in the dark.
+    dark_vision: bool,
+}
+
+impl Ai {
+    pub fn new(sight_radius: i32, dark_vision: bool) -> Self {
+        Ai { alertness: Alertness::Wandering, destination: None, heard: None, sight_radius, dark_vision }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Alertness {
+    /// Does not see anything, and only wakes up to loud noises.
+    Asleep,
+    /// Idly walks around.
+    Wandering,
+    /// Heads to a noise it heard.
+    Investigating,
+    /// Chases the player, or heads to where it last saw them.
+    Hunting,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Noise {
+    Fighting,
+    Door,
+    Footsteps,
+    Alarm,
+}
+
+impl Noise {
+    fn radius(self) -> f32 {
+        match self {
+            Noise::Fighting => NOISE_FIGHTING,
+            Noise::Door => NOISE_DOOR,
+            Noise::Footsteps => NOISE_FOOTSTEPS,
+            Noise::Alarm => ALARM_RADIUS,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Noise::Fighting => "fighting",
+            Noise::Door => "a door",
+            Noise::Footsteps => "footsteps",
+            Noise::Alarm => "the alarm",
+        }
+    }
+}
+
+/// Let the monsters around hear a noise made at the given position, and come to investigate.
+/// Monsters already hunting the player do not get distracted.
+fn make_noise(noise: Noise, (x, y): (i32, i32), map: &Map, world: &mut World) {
+    for (id, ai) in world.ais.iter_mut() {
+        if ai.alertness == Alertness::Hunting {
+            continue;
+        }
+        let Position { x: monster_x, y: monster_y } = world.positions[id];
+        let walls = line((x, y), (monster_x, monster_y)).iter()
+            .filter(|&&(x, y)| !map[(x, y)].transparent)
+            .count();
+        let distance = (((monster_x - x).pow(2) + (monster_y - y).pow(2)) as f32).sqrt() +
+            walls as f32 * NOISE_WALL_PENALTY;
+        let range = if ai.alertness == Alertness::Asleep {
+            noise.radius() * ASLEEP_HEARING
+        } else {
+            noise.radius()
+        };
+        if distance <= range {
+            ai.heard = Some((noise, ai.alertness));
+            ai.alertness = Alertness::Investigating;
+            ai.destination = Some((x, y));
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum EffectKind {
+    Poison,
+    Confusion,
+    Haste,
+    Slow,
+    Regeneration,
+    Blindness,
+}
+
+impl EffectKind {
+    /// Adjective describing an object under this effect, e.g. "orc is poisoned".
+    fn adjective(self) -> &'static str {
+        use self::EffectKind::*;
+        match self {
+            Poison => "poisoned",
+            Confusion => "confused",
+            Haste => "hasted",
+            Slow => "slowed",
+            Regeneration => "regenerating",
+            Blindness => "blinded",
+        }
+    }
+
+    /// Short label displayed in the panel.
+    fn label(self) -> &'static str {
+        use self::EffectKind::*;
+        match self {
+            Poison => "PSN",
+            Confusion => "CNF",
+            Haste => "HST",
+            Slow => "SLW",
+            Regeneration => "RGN",
+            Blindness => "BLD",
+        }
+    }
+
+    fn color(self) -> Color {
+        use self::EffectKind::*;
+        match self {
+            Poison => colors::GREEN,
+            Confusion => colors::LIGHT_MAGENTA,
+            Haste => colors::LIGHT_BLUE,
+            Slow => colors::LIGHT_SEPIA,
+            Regeneration => colors::LIGHT_RED,
+            Blindness => colors::LIGHT_GREY,
+        }
+    }
 }
 
+/// A status effect lasting for a given number of turns.
 #[derive(Copy, Clone, Debug, PartialEq)]
-struct Ai;
+struct Effect {
+    kind: EffectKind,
+    turns: i32,
+}
+
+/// Light emitted by an object. It is brightest on the object's tile and fades out to nothing at
+/// the given radius, linearly with a falloff of 1, and faster with a higher falloff.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Light {
+    radius: i32,
+    color: Color,
+    falloff: f32,
+}
 
+/// Blueprint of an entity, with all its components in one place. Map generation builds levels
+/// out of objects before spawning them into the `World`, and items taken out of the world are
+/// kept as objects in the inventory.
+#[derive(Clone)]
 struct Object {
     x: i32,
     y: i32,
@@ -121,10 +592,20 @@ struct Object {
     name: String,
     color: Color,
     traversable: bool,
-    alive: bool,
+    /// Drawn even out of the field of view, once its tile was explored.
+    always_visible: bool,
+    light: Option<Light>,
+    /// Energy gained every turn.
+    speed: i32,
+    /// Energy accumulated so far, spent by acting.
+    energy: i32,
     fighter: Option<Fighter>,
     ai: Option<Ai>,
+    loot: Option<LootTable>,
     item: Option<Item>,
+    /// Number of items in the stack, for stackable items.
+    count: i32,
+    effects: Vec<Effect>,
 }
 
 impl Object {
@@ -136,14 +617,32 @@ impl Object {
             name: name.to_string(),
             color,
             traversable,
-            alive: false,
+            always_visible: false,
+            light: None,
+            speed: NORMAL_SPEED,
+            energy: 0,
             fighter: None,
             ai: None,
+            loot: None,
             item: None,
+            count: 1,
+            effects: Vec::new(),
+        }
+    }
+
+    pub fn is_stairs(&self) -> bool {
+        self.name == "stairs"
+    }
+
+    /// Name including the number of items in the stack, if there are several.
+    pub fn display_name(&self) -> String {
+        if self.count > 1 {
+            format!("{} (x{})", self.name, self.count)
+        } else {
+            self.name.clone()
         }
     }
 
-    // Movement
     pub fn pos(&self) -> (i32, i32) {
         (self.x, self.y)
     }
@@ -151,584 +650,4346 @@ impl Object {
         self.x = x;
         self.y = y;
     }
-    pub fn distance_to(&self, other: &Object) -> f32 {
-        (((self.x - other.x).pow(2) + (self.y - other.y).pow(2)) as f32).sqrt()
-    }
+}
 
-    // Fight
-    pub fn take_damage(&mut self, damage: i32, messages: &mut Messages) {
-        // Apply damage if possible
-        if let Some(fighter) = self.fighter.as_mut() {
-            if damage > 0 {
-                fighter.hp -= damage;
-            }
-            let fighter = &*fighter;  // Change into an immutable reference.
-            if fighter.hp <= 0 {
-                self.alive = false;
-                fighter.on_death.callback(self, messages);
-            }
-        }
-    }
-    pub fn attack(&mut self, target: &mut Object, messages: &mut Messages) {
-        let damage = self.fighter.map_or(0, |f| f.power) - target.fighter.map_or(0, |f| f.defence);
-        if damage > 0 {
-            log_message(messages, format!("{} attacks {} for {} hit points!", self.name, target.name, damage), colors::WHITE);
-            target.take_damage(damage, messages);
-        } else {
-            log_message(messages, format!("{} attacks {} but it has no effect!", self.name, target.name), colors::WHITE);
-        }
-    }
+/// Handle to an entity of the world. Removing an entity bumps the generation of its slot, so old
+/// handles to it never refer to another entity that reuses the slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Entity {
+    index: usize,
+    generation: u32,
+}
 
-    // Graphics
-    pub fn draw(&self, con: &mut Console) {
-        con.set_default_foreground(self.color);
-        con.put_char(self.x, self.y, self.char, BackgroundFlag::None);
-    }
-    pub fn clear(&self, con: &mut Console) {
-        con.put_char(self.x, self.y, ' ', BackgroundFlag::None);
-    }
+// Components
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Position {
+    x: i32,
+    y: i32,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
-enum Item {
-    Heal,
+struct Renderable {
+    char: char,
+    color: Color,
+    /// Drawn even out of the field of view, once its tile was explored.
+    always_visible: bool,
 }
 
-fn pick_item_up(
-    object_id: usize,
-    objects: &mut Vec<Object>,
-    inventory: &mut Vec<Object>,
-    messages: &mut Messages
-    ) {
-    if inventory.len() >= 26 {
-        log_message(messages,
-                    format!("Your inventory is full, cannot pick up {}.",
-                            objects[object_id].name
-                    ),
-                    colors::RED);
-    } else {
-        let item = objects.swap_remove(object_id);
-        log_message(messages,
-                    format!("You picked up a {}.", item.name
-                    ),
-                    colors::RED
-        );
-        inventory.push(item);
+impl Renderable {
+    pub fn draw(&self, con: &mut Console, Position { x, y }: Position, camera: &Camera) {
+        if let Some((x, y)) = camera.to_screen(x, y) {
+            con.set_default_foreground(self.color);
+            con.put_char(x, y, self.char, BackgroundFlag::None);
+        }
+    }
+    pub fn clear(&self, con: &mut Console, Position { x, y }: Position, camera: &Camera) {
+        if let Some((x, y)) = camera.to_screen(x, y) {
+            con.put_char(x, y, ' ', BackgroundFlag::None);
+        }
     }
 }
 
+/// Marks entities that nothing else can stand on.
 #[derive(Clone, Copy, Debug, PartialEq)]
-enum PlayerAction {
-    TookTurn,
-    DidntTakeTurn,
-    Exit,
+struct BlocksMovement;
+
+/// Something taking turns: it gains energy every turn according to its speed, and spends it by
+/// acting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Actor {
+    speed: i32,
+    energy: i32,
 }
 
-/// Move object by the given amount
-/// Note: because we need to pass the object vec, we have a borrow issue if we write this as a
-///     method: self (of type Object) would be borrowed as mutable but the vector of objects would
-///     contain a ref to self and the borrow checked wouldn't allow that.
-fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) {
-    let (x, y) = objects[id].pos();
-    if is_traversable(x + dx, y + dy, map, objects) {
-        objects[id].set_pos(x + dx, y + dy);
-    }
+/// Storage for one kind of component, indexed by entity.
+struct Components<T> {
+    slots: Vec<Option<(Entity, T)>>,
 }
-fn move_by_or_attack(id: usize, dx: i32, dy: i32, map: &Map,
-                     objects: &mut Vec<Object>, messages: &mut Messages) {
-    // The coordinates the player is moving to/attacking.
-    let x = objects[id].x + dx;
-    let y = objects[id].y + dy;
 
-    // Try to find an attackable object there.
-    let target_id = objects.iter().position(|o| {
-        o.fighter.is_some() && o.pos() == (x, y)
-    });
+impl<T> Components<T> {
+    pub fn new() -> Self {
+        Components { slots: vec![] }
+    }
 
-    // Attack if such an object is found.
-    match target_id {
-        Some(target_id) => {
-            let (player, target) = mut_two(objects, PLAYER_ID, target_id);
-            player.attack(target, messages);
-        },
-        None => move_by(id, dx, dy, map, objects),
+    pub fn insert(&mut self, id: Entity, component: T) {
+        if self.slots.len() <= id.index {
+            self.slots.resize_with(id.index + 1, || None);
+        }
+        self.slots[id.index] = Some((id, component));
     }
-}
 
-fn move_towards(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mut [Object]) {
-    // Vector from object to target.
-    let dx = target_x - objects[id].x;
-    let dy = target_y - objects[id].y;
-    let dist = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
+    pub fn remove(&mut self, id: Entity) -> Option<T> {
+        if !self.contains(id) {
+            return None;
+        }
+        self.slots[id.index].take().map(|(_, component)| component)
+    }
 
-    // Normalise the vector.
-    let dx = (dx as f32 / dist).round() as i32;
-    let dy = (dy as f32 / dist).round() as i32;
+    pub fn contains(&self, id: Entity) -> bool {
+        self.get(id).is_some()
+    }
 
-    move_by(id, dx, dy, map, objects);
-}
+    pub fn get(&self, id: Entity) -> Option<&T> {
+        match self.slots.get(id.index) {
+            Some(Some((owner, component))) if *owner == id => Some(component),
+            _ => None,
+        }
+    }
 
-fn ai_take_turn(monster_id: usize, map: &Map, objects: &mut [Object], messages: &mut Messages,
-                fov_map: &FovMap) {
-    // Basic monster takes its turn; if you can see it, it can see you.
-    let (monster_x, monster_y) = objects[monster_id].pos();
-    if fov_map.is_in_fov(monster_x, monster_y) {
-        if objects[monster_id].distance_to(&objects[PLAYER_ID]) >= 2.0 {
-            // Move towards the player.
-            move_towards(monster_id, objects[PLAYER_ID].x, objects[PLAYER_ID].y, map, objects);
-        } else {
-            let (monster, player) = mut_two(objects, monster_id, PLAYER_ID);
-            monster.attack(player, messages);
+    pub fn get_mut(&mut self, id: Entity) -> Option<&mut T> {
+        match self.slots.get_mut(id.index) {
+            Some(Some((owner, component))) if *owner == id => Some(component),
+            _ => None,
         }
     }
-}
 
-#[derive(Clone, Copy, Debug)]
-struct Tile {
-    explored: bool,
-    traversable: bool,
-    transparent: bool,
-}
+    /// The entities having this component, with it, in entity order.
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, &T)> {
+        self.slots.iter().filter_map(|slot| slot.as_ref().map(|(id, component)| (*id, component)))
+    }
 
-impl Tile {
-    pub fn empty() -> Self {
-        Tile { explored: false, traversable: true, transparent: true }
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Entity, &mut T)> {
+        self.slots.iter_mut().filter_map(|slot| slot.as_mut().map(|(id, component)| (*id, component)))
     }
 
-    pub fn wall() -> Self {
-        Tile { explored: false, traversable: false, transparent: false }
+    /// Handles to the entities having this component, for systems which change the world while
+    /// going over them.
+    pub fn ids(&self) -> Vec<Entity> {
+        self.iter().map(|(id, _)| id).collect()
     }
 }
 
-type Map = Vec<Vec<Tile>>;
+impl<T> Index<Entity> for Components<T> {
+    type Output = T;
 
-#[derive(Clone, Copy, Debug)]
-struct Rect {
-    x1: i32,
-    y1: i32,
-    x2: i32,
-    y2: i32,
+    fn index(&self, id: Entity) -> &T {
+        self.get(id).expect("No such component")
+    }
 }
 
-impl Rect {
-    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
-        Rect { x1: x, y1: y, x2: x + w, y2: y + h }
-    }
-    pub fn center(&self) -> (i32, i32) {
-        ((self.x1 + self.x2) / 2, (self.y1 + self.y2) / 2)
-    }
-    pub fn intersects_with(&self, other: &Rect) -> bool {
-        let x_intersects: bool = self.x2 >= other.x1 && self.x1 <= other.x2;
-        let y_intersects: bool = self.y2 >= other.y1 && self.y1 <= other.y2;
-        x_intersects && y_intersects
+impl<T> IndexMut<Entity> for Components<T> {
+    fn index_mut(&mut self, id: Entity) -> &mut T {
+        self.get_mut(id).expect("No such component")
     }
 }
 
-type Messages = Vec<(String, Color)>;
+/// The entities on each tile of the map, so that finding what stands somewhere does not go over
+/// all the entities. Every tile lists its entities in entity order.
+struct SpatialIndex {
+    tiles: Grid<Vec<Entity>>,
+}
 
-fn main() {
-    println!("Hello, world!");
+impl SpatialIndex {
+    pub fn new() -> Self {
+        SpatialIndex { tiles: Grid::new(MAP_WIDTH, MAP_HEIGHT, vec![]) }
+    }
 
-    let mut root = Root::initializer()
-    .font("arial10x10.png", FontLayout::Tcod)
-    .font_type(FontType::Greyscale)
-    .size(SCREEN_WIDTH, SCREEN_HEIGHT)
-    .title("Rust/libtcod tutorial")
-    .init();
+    pub fn insert(&mut self, id: Entity, Position { x, y }: Position) {
+        if let Some(entities) = self.tiles.get_mut(x, y) {
+            let at = entities.iter().position(|other| other.index > id.index).unwrap_or(entities.len());
+            entities.insert(at, id);
+        }
+    }
 
-    let mut con = Offscreen::new(MAP_WIDTH, MAP_HEIGHT);
-    let mut panel = Offscreen::new(MAP_WIDTH, PANEL_HEIGHT);
-    let mut messages = vec![];
+    pub fn remove(&mut self, id: Entity, Position { x, y }: Position) {
+        if let Some(entities) = self.tiles.get_mut(x, y) {
+            entities.retain(|&other| other != id);
+        }
+    }
 
-    tcod::system::set_fps(LIMIT_FPS);
+    pub fn at(&self, x: i32, y: i32) -> &[Entity] {
+        self.tiles.get(x, y).map_or(&[], |entities| entities)
+    }
+}
 
-    let mut objects = Vec::new();
-    let (mut map, (player_x, player_y)) = make_map(&mut objects);
+struct Slot {
+    generation: u32,
+    used: bool,
+}
 
-    let mut player = Object::new(player_x, player_y, '@', "player", colors::WHITE, false);
-    player.alive = true;
-    player.fighter = Some(Fighter { max_hp: 30, hp: 30, defence: 2, power: 5, on_death: DeathCallback::Player });
+/// Everything on the current level. Entities are only handles, and their data is split in one
+/// storage per kind of component. The game logic is made of systems: functions going over the
+/// entities which have the components they need, such as `tick_effects`, `ai_take_turn`,
+/// `play_until_player_turn` and `render_all`.
+struct World {
+    slots: Vec<Slot>,
+    /// Indices of the empty slots, reused first.
+    free: Vec<usize>,
+    /// Where the entities are. Positions only change through `set_pos`, which keeps it in sync.
+    spatial: SpatialIndex,
+    /// Subscribers to the game events, kept from one level to the next.
+    events: EventBus,
+    /// Dungeon level of the current level.
+    depth: u32,
 
-    // let npc = Object::new(player.x - 1, player.y -3, '@', colors::YELLOW);
-    objects.insert(PLAYER_ID, player);
+    positions: Components<Position>,
+    renderables: Components<Renderable>,
+    names: Components<String>,
+    blocks: Components<BlocksMovement>,
+    lights: Components<Light>,
+    actors: Components<Actor>,
+    fighters: Components<Fighter>,
+    ais: Components<Ai>,
+    /// What monsters drop when they die.
+    loot: Components<LootTable>,
+    items: Components<Item>,
+    /// Number of items in the stack, for items.
+    stacks: Components<i32>,
+    /// Status effects, for fighters.
+    effects: Components<Vec<Effect>>,
+}
 
-    // Fill the field-of-view map
-    let mut fov_map = FovMap::new(MAP_WIDTH, MAP_HEIGHT);
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
-            fov_map.set(x, y,
-                        map[x as usize][y as usize].transparent,
-                        map[x as usize][y as usize].traversable);
+impl World {
+    pub fn new() -> Self {
+        World {
+            slots: vec![],
+            free: vec![],
+            spatial: SpatialIndex::new(),
+            events: EventBus::new(),
+            depth: 1,
+            positions: Components::new(),
+            renderables: Components::new(),
+            names: Components::new(),
+            blocks: Components::new(),
+            lights: Components::new(),
+            actors: Components::new(),
+            fighters: Components::new(),
+            ais: Components::new(),
+            loot: Components::new(),
+            items: Components::new(),
+            stacks: Components::new(),
+            effects: Components::new(),
         }
     }
 
-    let mut prev_player_position = (-1, -1);
-
-    // A warm welcoming message!
-    log_message(
-        &mut messages,
-        "Welcome stranger! Prepare to perish in the Tombs of the Ancient Kings.",
-        colors::RED,
-    );
-
-    let mut mouse = Default::default();
-    let mut key = Default::default();
+    /// Tell every subscriber about something which just happened.
+    pub fn emit(&mut self, event: GameEvent, messages: &mut Messages) {
+        self.events.publish(&event, messages);
+    }
 
-    let mut inventory = vec![];
+    /// Create an entity with the components of the given object.
+    pub fn spawn(&mut self, object: Object) -> Entity {
+        let id = match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index];
+                slot.used = true;
+                Entity { index, generation: slot.generation }
+            }
+            None => {
+                self.slots.push(Slot { generation: 0, used: true });
+                Entity { index: self.slots.len() - 1, generation: 0 }
+            }
+        };
 
-    while !root.window_closed() {
-        // clear the screen of the previous frame
-        con.clear();
+        self.set_pos(id, object.x, object.y);
+        self.renderables.insert(id, Renderable {
+            char: object.char,
+            color: object.color,
+            always_visible: object.always_visible,
+        });
+        self.names.insert(id, object.name);
+        if !object.traversable {
+            self.blocks.insert(id, BlocksMovement);
+        }
+        if let Some(light) = object.light {
+            self.lights.insert(id, light);
+        }
+        if object.fighter.is_some() || object.ai.is_some() {
+            self.actors.insert(id, Actor { speed: object.speed, energy: object.energy });
+        }
+        if let Some(fighter) = object.fighter {
+            self.fighters.insert(id, fighter);
+            self.effects.insert(id, object.effects);
+        }
+        if let Some(ai) = object.ai {
+            self.ais.insert(id, ai);
+        }
+        if let Some(loot) = object.loot {
+            self.loot.insert(id, loot);
+        }
+        if let Some(item) = object.item {
+            self.items.insert(id, item);
+            self.stacks.insert(id, object.count);
+        }
+        id
+    }
 
-        con.set_default_foreground(colors::WHITE);
-        let player = &mut objects[PLAYER_ID];
+    /// Remove an entity from the world, gathering its components back into an object.
+    pub fn despawn(&mut self, id: Entity) -> Option<Object> {
+        if !self.contains(id) {
+            return None;
+        }
+        let slot = &mut self.slots[id.index];
+        slot.generation += 1;
+        slot.used = false;
+        self.free.push(id.index);
 
-        match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
-            Some((_, Event::Mouse(m))) => mouse = m,
-            Some((_, Event::Key(k))) => key = k,
-            _ => key = Default::default(),
+        let position = self.positions.remove(id);
+        if let Some(position) = position {
+            self.spatial.remove(id, position);
         }
+        let renderable = self.renderables.remove(id);
+        let name = self.names.remove(id);
+        let blocks = self.blocks.remove(id).is_some();
+        let light = self.lights.remove(id);
+        let actor = self.actors.remove(id);
+        let fighter = self.fighters.remove(id);
+        let ai = self.ais.remove(id);
+        let loot = self.loot.remove(id);
+        let item = self.items.remove(id);
+        let stack = self.stacks.remove(id);
+        let effects = self.effects.remove(id);
 
-        let fov_recompute = prev_player_position != (player.x, player.y);
-        render_all(&mut root,
-                   &mut con,
-                   &mut panel,
-                   mouse,
-                   &objects,
-                   &messages,
-                   &mut map,
-                   &mut fov_map,
-                   fov_recompute);
+        let (Position { x, y }, renderable) = (position?, renderable?);
+        let mut object = Object::new(x, y, renderable.char, &name?, renderable.color, !blocks);
+        object.always_visible = renderable.always_visible;
+        object.light = light;
+        if let Some(actor) = actor {
+            object.speed = actor.speed;
+            object.energy = actor.energy;
+        }
+        object.fighter = fighter;
+        object.ai = ai;
+        object.loot = loot;
+        object.item = item;
+        object.count = stack.unwrap_or(1);
+        object.effects = effects.unwrap_or_default();
+        Some(object)
+    }
 
-        root.flush();
+    pub fn contains(&self, id: Entity) -> bool {
+        self.slots.get(id.index).map_or(false, |slot| slot.used && slot.generation == id.generation)
+    }
 
-        let player = &mut objects[PLAYER_ID];
-        prev_player_position = (player.x, player.y);
+    /// Handles to all the entities, in entity order.
+    pub fn entities(&self) -> Vec<Entity> {
+        self.slots.iter().enumerate()
+            .filter(|(_, slot)| slot.used)
+            .map(|(index, slot)| Entity { index, generation: slot.generation })
+            .collect()
+    }
 
-        // Handle keys and exit if needed
-        let player_action = handle_keys(key, &mut root, &map, &mut objects, &mut inventory, &mut messages);
-        if player_action == PlayerAction::Exit {
-            break;
+    // Movement
+    pub fn pos(&self, id: Entity) -> (i32, i32) {
+        let Position { x, y } = self.positions[id];
+        (x, y)
+    }
+    pub fn set_pos(&mut self, id: Entity, x: i32, y: i32) {
+        if let Some(&previous) = self.positions.get(id) {
+            self.spatial.remove(id, previous);
         }
-        if objects[PLAYER_ID].alive && player_action != PlayerAction::DidntTakeTurn {
-            for o in objects.iter().filter(
-                |x| (x.name) != (objects[PLAYER_ID].name) &&
-                x.distance_to(&objects[PLAYER_ID]) < 5_f32 &&
-                x.fighter.is_some()
-                ) {
-                log_message(&mut messages, format!("The {} growls!", o.name), colors::DARK_RED);
-            }
-            for id in 0..objects.len() {
-                if objects[id].ai.is_some() {
-                    ai_take_turn(id, &map, &mut objects, &mut messages, &fov_map);
+        self.positions.insert(id, Position { x, y });
+        self.spatial.insert(id, Position { x, y });
+    }
+    pub fn distance(&self, id: Entity, other: Entity) -> f32 {
+        let ((x, y), (other_x, other_y)) = (self.pos(id), self.pos(other));
+        (((x - other_x).pow(2) + (y - other_y).pow(2)) as f32).sqrt()
+    }
+
+    pub fn is_stairs(&self, id: Entity) -> bool {
+        self.names.get(id).map_or(false, |name| name == "stairs")
+    }
+
+    /// All the entities at the given position, in entity order.
+    pub fn entities_at(&self, x: i32, y: i32) -> &[Entity] {
+        self.spatial.at(x, y)
+    }
+
+    /// The fighter standing at the given position, if any.
+    pub fn fighter_at(&self, x: i32, y: i32) -> Option<Entity> {
+        self.entities_at(x, y).iter().cloned().find(|&id| self.fighters.contains(id))
+    }
+
+    /// The entity preventing others from stepping on the given position, if any.
+    pub fn blocking_at(&self, x: i32, y: i32) -> Option<Entity> {
+        self.entities_at(x, y).iter().cloned().find(|&id| self.blocks.contains(id))
+    }
+
+    pub fn items_at(&self, x: i32, y: i32) -> impl Iterator<Item = Entity> + '_ {
+        self.entities_at(x, y).iter().cloned().filter(move |&id| self.items.contains(id))
+    }
+
+    // Fight
+    /// Whether the entity can still fight, i.e. it is not dead.
+    pub fn is_alive(&self, id: Entity) -> bool {
+        self.fighters.contains(id)
+    }
+
+    // Time
+    /// Energy gained every turn, with all modifiers applied.
+    pub fn speed(&self, id: Entity) -> i32 {
+        let mut speed = self.actors.get(id).map_or(NORMAL_SPEED, |actor| actor.speed);
+        if self.has_effect(id, EffectKind::Haste) {
+            speed *= 2;
+        }
+        if self.has_effect(id, EffectKind::Slow) {
+            speed /= 2;
+        }
+        max(1, speed)
+    }
+
+    // Vision
+    /// Radius of the field of view, with all modifiers applied.
+    pub fn sight_radius(&self, id: Entity) -> i32 {
+        if self.has_effect(id, EffectKind::Blindness) {
+            BLIND_SIGHT_RADIUS
+        } else {
+            self.ais.get(id).map_or(SIGHT_RADIUS, |ai| ai.sight_radius)
+        }
+    }
+
+    // Status effects
+    pub fn has_effect(&self, id: Entity, kind: EffectKind) -> bool {
+        self.effects.get(id).map_or(false, |effects| effects.iter().any(|e| e.kind == kind))
+    }
+}
+
+/// Apply an effect for the given number of turns. An effect that is already active lasts for the
+/// longest of its remaining and new durations.
+fn apply_effect(id: Entity, kind: EffectKind, turns: i32, world: &mut World, messages: &mut Messages) {
+    let effects = match world.effects.get_mut(id) {
+        Some(effects) => effects,
+        None => return,
+    };
+    if let Some(effect) = effects.iter_mut().find(|e| e.kind == kind) {
+        effect.turns = max(effect.turns, turns);
+    } else {
+        effects.push(Effect { kind, turns });
+        log_message(messages, format!("{} is {}!", world.names[id], kind.adjective()), kind.color());
+    }
+}
+
+/// Apply the per-turn part of all active effects, then expire the ones that ran out.
+fn tick_effects(map: &Map, world: &mut World, messages: &mut Messages) {
+    for id in world.effects.ids() {
+        for effect in world.effects[id].clone() {
+            match effect.kind {
+                EffectKind::Poison => take_damage(id, POISON_DAMAGE, DamageType::Poison, map, world, messages),
+                EffectKind::Regeneration => heal(id, REGENERATION_AMOUNT, world),
+                _ => {}
+            }
+        }
+
+        // Dying ends all effects.
+        let effects = match world.effects.get_mut(id) {
+            Some(effects) => effects,
+            None => continue,
+        };
+        for effect in effects.iter_mut() {
+            effect.turns -= 1;
+        }
+        let (expired, active): (Vec<Effect>, Vec<Effect>) =
+            effects.iter().partition(|e| e.turns <= 0);
+        *effects = active;
+        for effect in expired {
+            log_message(messages,
+                        format!("{} is no longer {}.", world.names[id], effect.kind.adjective()),
+                        colors::LIGHT_GREY);
+        }
+    }
+}
+
+// Fight
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DamageType {
+    Physical,
+    Fire,
+    Cold,
+    Poison,
+    Lightning,
+}
+
+const DAMAGE_TYPES: [DamageType; 5] =
+    [DamageType::Physical, DamageType::Fire, DamageType::Cold, DamageType::Poison, DamageType::Lightning];
+
+impl DamageType {
+    pub fn name(self) -> &'static str {
+        match self {
+            DamageType::Physical => "physical",
+            DamageType::Fire => "fire",
+            DamageType::Cold => "cold",
+            DamageType::Poison => "poison",
+            DamageType::Lightning => "lightning",
+        }
+    }
+}
+
+/// How a fighter takes a type of damage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Affinity {
+    Normal,
+    /// Takes half the damage.
+    Resistant,
+    Immune,
+    /// Takes twice the damage.
+    Vulnerable,
+}
+
+impl Affinity {
+    pub fn apply(self, damage: i32) -> i32 {
+        match self {
+            Affinity::Normal => damage,
+            Affinity::Resistant => damage / 2,
+            Affinity::Immune => 0,
+            Affinity::Vulnerable => damage * 2,
+        }
+    }
+
+    /// Affinity given by two sources at once, such as a fighter and their armour: immunity wins,
+    /// and resistance and vulnerability cancel each other out.
+    pub fn combine(self, other: Affinity) -> Affinity {
+        use self::Affinity::*;
+        match (self, other) {
+            (Immune, _) | (_, Immune) => Immune,
+            (Resistant, Vulnerable) | (Vulnerable, Resistant) => Normal,
+            (Normal, affinity) | (affinity, _) => affinity,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Affinity::Normal => "normal",
+            Affinity::Resistant => "resistant",
+            Affinity::Immune => "immune",
+            Affinity::Vulnerable => "vulnerable",
+        }
+    }
+}
+
+/// The affinity of a fighter or a piece of armour to each type of damage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Resistances([Affinity; 5]);
+
+impl Resistances {
+    pub fn none() -> Self {
+        Resistances([Affinity::Normal; 5])
+    }
+
+    pub fn with(mut self, kind: DamageType, affinity: Affinity) -> Self {
+        self.0[kind as usize] = affinity;
+        self
+    }
+
+    pub fn get(self, kind: DamageType) -> Affinity {
+        self.0[kind as usize]
+    }
+}
+
+/// Describe how a fighter takes the types of damage they do not take normally, one per line.
+fn describe_affinities(fighter: &Fighter) -> String {
+    let lines: Vec<String> = DAMAGE_TYPES.iter()
+        .map(|&kind| (kind, fighter.affinity(kind)))
+        .filter(|&(_, affinity)| affinity != Affinity::Normal)
+        .map(|(kind, affinity)| format!("{} to {}.", capitalize(affinity.description()), kind.name()))
+        .collect();
+    if lines.is_empty() {
+        "No resistances.".into()
+    } else {
+        lines.join("\n")
+    }
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Hurt a fighter, taking their resistances to the type of damage into account.
+fn take_damage(id: Entity, damage: i32, kind: DamageType, map: &Map, world: &mut World, messages: &mut Messages) {
+    // Apply damage if possible
+    if let Some(fighter) = world.fighters.get_mut(id) {
+        let affinity = fighter.affinity(kind);
+        let dealt = fighter.damage_from(damage, kind);
+        if dealt > 0 {
+            fighter.hp -= dealt;
+        }
+        let (hp, on_death) = (fighter.hp, fighter.on_death);
+        if damage > 0 {
+            let (name, position) = (world.names[id].clone(), world.pos(id));
+            let event = GameEvent::Damaged { target: id, name, position, kind, affinity, damage: dealt, hp };
+            world.emit(event, messages);
+        }
+        if hp <= 0 {
+            on_death.callback(id, map, world, messages);
+        }
+    }
+}
+
+fn heal(id: Entity, amount: i32, world: &mut World) {
+    if let Some(fighter) = world.fighters.get_mut(id) {
+        fighter.hp = min(fighter.hp + amount, fighter.max_hp);
+    }
+}
+
+/// Dice rolled for damage, such as 1d6+2: the sum of `count` rolls of a die with `sides` faces,
+/// plus a bonus.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Dice {
+    count: i32,
+    sides: i32,
+    bonus: i32,
+}
+
+impl Dice {
+    pub fn new(count: i32, sides: i32, bonus: i32) -> Self {
+        Dice { count, sides, bonus }
+    }
+
+    pub fn roll(self) -> i32 {
+        (0..self.count).map(|_| rng().gen_range(1, self.sides + 1)).sum::<i32>() + self.bonus
+    }
+}
+
+impl fmt::Display for Dice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}d{}", self.count, self.sides)?;
+        if self.bonus > 0 {
+            write!(f, "+{}", self.bonus)
+        } else if self.bonus < 0 {
+            write!(f, "{}", self.bonus)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HitRoll {
+    Miss,
+    Hit,
+    Critical,
+}
+
+/// Roll to hit, with the attacker's accuracy against the target's evasion.
+fn roll_to_hit(accuracy: i32, evasion: i32) -> HitRoll {
+    let chance = BASE_HIT_CHANCE + HIT_CHANCE_PER_POINT * (accuracy - evasion);
+    let chance = chance.clamp(MIN_HIT_CHANCE, MAX_HIT_CHANCE);
+    let roll = rng().gen_range(1, 101);
+    if roll <= CRITICAL_CHANCE {
+        HitRoll::Critical
+    } else if roll <= chance {
+        HitRoll::Hit
+    } else {
+        HitRoll::Miss
+    }
+}
+
+/// Damage dealt by a hit: the damage dice, rolled twice for a critical hit, minus the target's
+/// armour. Anything which can hurt at all deals at least MIN_DAMAGE.
+fn roll_damage(dice: Dice, armour: i32, hit: HitRoll) -> i32 {
+    let rolled = match hit {
+        HitRoll::Miss => return 0,
+        HitRoll::Hit => dice.roll(),
+        HitRoll::Critical => dice.roll() + dice.roll(),
+    };
+    if rolled <= 0 {
+        0
+    } else {
+        max(rolled - armour, MIN_DAMAGE)
+    }
+}
+
+fn attack(attacker: Entity, target: Entity, map: &Map, world: &mut World, messages: &mut Messages) {
+    let (attacking, defending) = (world.fighters[attacker], world.fighters[target]);
+    let hit = roll_to_hit(attacking.accuracy, defending.evasion);
+    let damage = roll_damage(attacking.damage, defending.total_armour(), hit);
+    let (attacker_name, target_name) = (world.names[attacker].clone(), world.names[target].clone());
+    let dealt = defending.damage_from(damage, attacking.damage_type);
+    let event =
+        GameEvent::Attacked { attacker, target, attacker_name, target_name, projectile: None, hit, damage: dealt };
+    world.emit(event, messages);
+    if damage > 0 {
+        take_damage(target, damage, attacking.damage_type, map, world, messages);
+        if let Some(effect) = attacking.attack_effect {
+            if world.fighters.contains(target) {
+                apply_effect(target, effect.kind, effect.turns, world, messages);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Item {
+    Heal,
+    Haste,
+    Regenerate,
+    Confuse,
+    Fireball,
+    Lightning,
+    Launcher(Launcher),
+    Ammo(AmmoKind),
+    /// Opens a locked door, and is used up doing so.
+    Key,
+    /// Worn by using it, replacing the armour worn until then.
+    Armour(ArmourKind),
+    Gold,
+}
+
+impl Item {
+    /// Weight of the item, which is also the damage it deals when thrown.
+    pub fn weight(self) -> i32 {
+        match self {
+            Item::Heal | Item::Haste | Item::Regenerate => 1,
+            Item::Confuse | Item::Fireball | Item::Lightning | Item::Gold => 0,
+            Item::Launcher(_) | Item::Armour(_) => 3,
+            Item::Ammo(_) | Item::Key => 1,
+        }
+    }
+
+    pub fn is_potion(self) -> bool {
+        matches!(self, Item::Heal | Item::Haste | Item::Regenerate)
+    }
+
+    /// Whether several such items are gathered in a single inventory slot.
+    pub fn is_stackable(self) -> bool {
+        matches!(self, Item::Ammo(_) | Item::Gold)
+    }
+}
+
+/// A ranged weapon, firing the given kind of ammunition.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Launcher {
+    ammo: AmmoKind,
+    damage: Dice,
+    range: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ArmourKind {
+    Leather,
+    /// Resists fire.
+    Salamander,
+}
+
+impl ArmourKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ArmourKind::Leather => "leather armour",
+            ArmourKind::Salamander => "salamander scale armour",
+        }
+    }
+
+    pub fn armour(self) -> i32 {
+        match self {
+            ArmourKind::Leather => 1,
+            ArmourKind::Salamander => 1,
+        }
+    }
+
+    pub fn resistances(self) -> Resistances {
+        match self {
+            ArmourKind::Leather => Resistances::none(),
+            ArmourKind::Salamander => Resistances::none().with(DamageType::Fire, Affinity::Resistant),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AmmoKind {
+    Arrow,
+    Bolt,
+}
+
+impl AmmoKind {
+    fn plural_name(self) -> &'static str {
+        match self {
+            AmmoKind::Arrow => "arrows",
+            AmmoKind::Bolt => "bolts",
+        }
+    }
+}
+
+enum UseResult {
+    UsedUp,
+    Cancelled,
+}
+
+/// Use the inventory item at the given index, removing it from the inventory if it was used up.
+fn use_item(
+    inventory_id: usize,
+    inventory: &mut Vec<Object>,
+    map: &Map,
+    world: &mut World,
+    messages: &mut Messages,
+    fov_map: &FovMap,
+    ) -> UseResult {
+    use self::Item::*;
+
+    let result = match inventory[inventory_id].item {
+        Some(Heal) => use_heal(world, messages),
+        Some(Haste) => use_potion(Haste, world, messages),
+        Some(Regenerate) => use_potion(Regenerate, world, messages),
+        Some(Confuse) => use_confuse(world, messages, fov_map),
+        Some(Fireball) => use_fireball(map, world, messages, fov_map),
+        Some(Lightning) => use_lightning(map, world, messages, fov_map),
+        Some(Armour(kind)) => wear_armour(kind, inventory, world, messages),
+        Some(Launcher(_)) => {
+            log_message(messages,
+                        format!("Press 'f' to fire the {}.", inventory[inventory_id].name),
+                        colors::WHITE);
+            UseResult::Cancelled
+        }
+        Some(Ammo(_)) | Some(Key) | Some(Gold) | None => {
+            log_message(messages,
+                        format!("The {} cannot be used.", inventory[inventory_id].name),
+                        colors::WHITE);
+            UseResult::Cancelled
+        }
+    };
+    if let UseResult::UsedUp = result {
+        let used = inventory.remove(inventory_id);
+        if let Some(item) = used.item {
+            world.emit(GameEvent::ItemUsed { item, name: used.name }, messages);
+        }
+    }
+    result
+}
+
+fn use_heal(world: &mut World, messages: &mut Messages) -> UseResult {
+    if let Some(&fighter) = world.fighters.get(PLAYER_ID) {
+        if fighter.hp == fighter.max_hp {
+            log_message(messages, "You are already at full health.", colors::RED);
+            return UseResult::Cancelled;
+        }
+        log_message(messages, "Your wounds start to feel better!", colors::LIGHT_VIOLET);
+        heal(PLAYER_ID, HEAL_AMOUNT, world);
+        return UseResult::UsedUp;
+    }
+    UseResult::Cancelled
+}
+
+fn use_potion(potion: Item, world: &mut World, messages: &mut Messages) -> UseResult {
+    apply_potion(potion, PLAYER_ID, world, messages);
+    UseResult::UsedUp
+}
+
+/// Apply the effect of a potion to whoever drinks it or gets splashed by it.
+fn apply_potion(potion: Item, target: Entity, world: &mut World, messages: &mut Messages) {
+    match potion {
+        Item::Heal => {
+            heal(target, HEAL_AMOUNT, world);
+            log_message(messages, format!("{} looks healthier.", world.names[target]), colors::LIGHT_VIOLET);
+        }
+        Item::Haste => apply_effect(target, EffectKind::Haste, HASTE_DURATION, world, messages),
+        Item::Regenerate => {
+            apply_effect(target, EffectKind::Regeneration, REGENERATION_DURATION, world, messages)
+        }
+        _ => {}
+    }
+}
+
+fn use_confuse(world: &mut World, messages: &mut Messages, fov_map: &FovMap) -> UseResult {
+    // Confuse the closest monster in range.
+    match closest_monster(CONFUSE_RANGE, world, fov_map) {
+        Some(monster_id) => {
+            apply_effect(monster_id, EffectKind::Confusion, CONFUSE_DURATION, world, messages);
+            UseResult::UsedUp
+        }
+        None => {
+            log_message(messages, "No enemy is close enough to confuse.", colors::RED);
+            UseResult::Cancelled
+        }
+    }
+}
+
+fn use_fireball(map: &Map, world: &mut World, messages: &mut Messages, fov_map: &FovMap) -> UseResult {
+    // The fireball bursts on the closest monster in range, burning everyone around it.
+    let monster_id = match closest_monster(FIREBALL_RANGE, world, fov_map) {
+        Some(monster_id) => monster_id,
+        None => {
+            log_message(messages, "No enemy is close enough to aim the fireball at.", colors::RED);
+            return UseResult::Cancelled;
+        }
+    };
+    let damage = FIREBALL_DAMAGE.roll();
+    let burst = world.pos(monster_id);
+    log_message(messages, format!("The fireball explodes on {}!", world.names[monster_id]), colors::ORANGE);
+    for id in world.fighters.ids() {
+        // Walls shelter from the blast.
+        if world.distance(monster_id, id) > FIREBALL_RADIUS || !in_line_of_sight(burst, world.pos(id), map) {
+            continue;
+        }
+        let dealt = world.fighters[id].damage_from(damage, DamageType::Fire);
+        let target_name = world.names[id].clone();
+        world.emit(GameEvent::SpellHit { spell: Item::Fireball, target: id, target_name, damage: dealt }, messages);
+        take_damage(id, damage, DamageType::Fire, map, world, messages);
+    }
+    UseResult::UsedUp
+}
+
+fn use_lightning(map: &Map, world: &mut World, messages: &mut Messages, fov_map: &FovMap) -> UseResult {
+    // Strike the closest monster in range.
+    match closest_monster(LIGHTNING_RANGE, world, fov_map) {
+        Some(monster_id) => {
+            let damage = LIGHTNING_DAMAGE.roll();
+            let dealt = world.fighters[monster_id].damage_from(damage, DamageType::Lightning);
+            let target_name = world.names[monster_id].clone();
+            let event = GameEvent::SpellHit { spell: Item::Lightning, target: monster_id, target_name, damage: dealt };
+            world.emit(event, messages);
+            take_damage(monster_id, damage, DamageType::Lightning, map, world, messages);
+            UseResult::UsedUp
+        }
+        None => {
+            log_message(messages, "No enemy is close enough to strike.", colors::RED);
+            UseResult::Cancelled
+        }
+    }
+}
+
+/// Put on a piece of armour, giving the one worn until then back to the inventory.
+fn wear_armour(kind: ArmourKind, inventory: &mut Vec<Object>, world: &mut World, messages: &mut Messages)
+    -> UseResult {
+    let fighter = match world.fighters.get_mut(PLAYER_ID) {
+        Some(fighter) => fighter,
+        None => return UseResult::Cancelled,
+    };
+    if let Some(previous) = fighter.worn.replace(kind) {
+        log_message(messages, format!("You take off the {}.", previous.name()), colors::LIGHT_GREEN);
+        inventory.push(make_item(Item::Armour(previous), 0, 0));
+    }
+    log_message(messages, format!("You put on the {}.", kind.name()), colors::LIGHT_GREEN);
+    UseResult::UsedUp
+}
+
+/// Find the closest monster in the player's field of view, up to a maximum range.
+fn closest_monster(max_range: i32, world: &World, fov_map: &FovMap) -> Option<Entity> {
+    let mut closest_monster = None;
+    let mut closest_distance = (max_range + 1) as f32;
+
+    for (id, _) in world.ais.iter() {
+        let (x, y) = world.pos(id);
+        if id != PLAYER_ID && world.fighters.contains(id) && fov_map.is_in_fov(x, y) {
+            let distance = world.distance(PLAYER_ID, id);
+            if distance < closest_distance {
+                closest_monster = Some(id);
+                closest_distance = distance;
+            }
+        }
+    }
+    closest_monster
+}
+
+/// Returns whether the item was picked up.
+fn pick_item_up(
+    object_id: Entity,
+    world: &mut World,
+    inventory: &mut Vec<Object>,
+    messages: &mut Messages
+    ) -> bool {
+    // Stackable items join the stack of identical items already in the inventory, if any.
+    let stack_id = inventory.iter().position(|item| {
+        item.item.map_or(false, Item::is_stackable) && item.item == world.items.get(object_id).cloned()
+    });
+
+    if stack_id.is_none() && inventory.len() >= 26 {
+        world.emit(GameEvent::InventoryFull { name: world.names[object_id].clone() }, messages);
+        false
+    } else {
+        let item = world.despawn(object_id).expect("No such entity");
+        if let Some(kind) = item.item {
+            world.emit(GameEvent::PickedUp { item: kind, name: item.display_name() }, messages);
+        }
+        match stack_id {
+            Some(stack_id) => inventory[stack_id].count += item.count,
+            None => inventory.push(item),
+        }
+        true
+    }
+}
+
+/// Take a single item out of the inventory stack at the given index.
+fn take_one(inventory: &mut Vec<Object>, inventory_id: usize) -> Object {
+    if inventory[inventory_id].count > 1 {
+        inventory[inventory_id].count -= 1;
+        let mut item = inventory[inventory_id].clone();
+        item.count = 1;
+        item
+    } else {
+        inventory.remove(inventory_id)
+    }
+}
+
+/// Fire a launcher from the inventory at a target picked by the player.
+fn fire(
+    root: &mut Root,
+    map: &Map,
+    world: &mut World,
+    inventory: &mut Vec<Object>,
+    messages: &mut Messages,
+    fov_map: &FovMap,
+    camera: &Camera,
+    ) -> PlayerAction {
+    let launchers: Vec<Launcher> = inventory.iter().filter_map(|item| match item.item {
+        Some(Item::Launcher(launcher)) => Some(launcher),
+        _ => None,
+    }).collect();
+    if launchers.is_empty() {
+        log_message(messages, "You have nothing to fire with.", colors::RED);
+        return PlayerAction::DidntTakeTurn;
+    }
+
+    // Use the first launcher we have ammunition for.
+    let loaded = launchers.iter().filter_map(|launcher| {
+        inventory.iter()
+            .position(|item| item.item == Some(Item::Ammo(launcher.ammo)))
+            .map(|ammo_id| (*launcher, ammo_id))
+    }).next();
+    let (launcher, ammo_id) = match loaded {
+        Some(loaded) => loaded,
+        None => {
+            log_message(messages,
+                        format!("You have no {} to fire.", launchers[0].ammo.plural_name()),
+                        colors::RED);
+            return PlayerAction::DidntTakeTurn;
+        }
+    };
+
+    match target_tile(root, world, fov_map, camera, launcher.range) {
+        Some(target) => {
+            let mut projectile = take_one(inventory, ammo_id);
+            let (landing, hit) = fly_projectile(PLAYER_ID, &projectile.name, target,
+                                                launcher.damage, map, world, messages);
+            // Ammunition that hit is lost, the rest can be picked up again.
+            if !hit {
+                projectile.set_pos(landing.0, landing.1);
+                world.spawn(projectile);
+            }
+            PlayerAction::TookTurn(FIRE_COST)
+        }
+        None => PlayerAction::DidntTakeTurn,
+    }
+}
+
+/// Throw an inventory item picked by the player at a target tile.
+fn throw(
+    root: &mut Root,
+    map: &Map,
+    world: &mut World,
+    inventory: &mut Vec<Object>,
+    messages: &mut Messages,
+    fov_map: &FovMap,
+    camera: &Camera,
+    ) -> PlayerAction {
+    let inventory_id = match inventory_menu(
+        inventory,
+        "Press the key next to an item to throw it, or any other to cancel.\n",
+        root) {
+        Some(inventory_id) => inventory_id,
+        None => return PlayerAction::DidntTakeTurn,
+    };
+    let target = match target_tile(root, world, fov_map, camera, THROW_RANGE) {
+        Some(target) => target,
+        None => return PlayerAction::DidntTakeTurn,
+    };
+
+    let mut thrown = take_one(inventory, inventory_id);
+    let item = thrown.item;
+    log_message(messages, format!("You throw the {}.", thrown.name), colors::WHITE);
+    let damage = Dice::new(0, 0, item.map_or(0, Item::weight));
+    let (landing, _) = fly_projectile(PLAYER_ID, &thrown.name, target, damage, map, world, messages);
+
+    match item {
+        Some(potion) if potion.is_potion() => {
+            // Potions shatter, splashing everyone around.
+            log_message(messages, format!("The {} shatters!", thrown.name), colors::LIGHT_BLUE);
+            for id in world.fighters.ids() {
+                let (x, y) = world.pos(id);
+                let (dx, dy) = (x - landing.0, y - landing.1);
+                let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
+                if distance <= SHATTER_RADIUS {
+                    apply_potion(potion, id, world, messages);
+                }
+            }
+        }
+        _ => {
+            thrown.set_pos(landing.0, landing.1);
+            world.spawn(thrown);
+        }
+    }
+    PlayerAction::TookTurn(THROW_COST)
+}
+
+/// Send a projectile flying in a straight line from the shooter towards the target tile,
+/// stopping before the first tile that cannot be seen through.
+///
+/// The first fighter on the way may be hit, with the shooter's accuracy, taking the rolled damage
+/// minus its armour. If it is missed, the projectile keeps flying. Returns the tile where the
+/// flight ended, and whether a fighter was hit there.
+fn fly_projectile(
+    shooter_id: Entity,
+    projectile_name: &str,
+    target: (i32, i32),
+    damage: Dice,
+    map: &Map,
+    world: &mut World,
+    messages: &mut Messages,
+    ) -> ((i32, i32), bool) {
+    let accuracy = world.fighters.get(shooter_id).map_or(0, |f| f.accuracy);
+    let mut landing = world.pos(shooter_id);
+    for (x, y) in line(landing, target) {
+        if !map[(x, y)].transparent {
+            break;
+        }
+        landing = (x, y);
+
+        if let Some(victim_id) = world.fighter_at(x, y) {
+            let victim = world.fighters[victim_id];
+            let hit = roll_to_hit(accuracy, victim.evasion);
+            let damage = roll_damage(damage, victim.total_armour(), hit);
+            world.emit(GameEvent::Attacked {
+                attacker: shooter_id,
+                target: victim_id,
+                attacker_name: world.names[shooter_id].clone(),
+                target_name: world.names[victim_id].clone(),
+                projectile: Some(projectile_name.to_string()),
+                hit,
+                damage: victim.damage_from(damage, DamageType::Physical),
+            }, messages);
+            if hit != HitRoll::Miss {
+                if damage > 0 {
+                    take_damage(victim_id, damage, DamageType::Physical, map, world, messages);
+                    make_noise(Noise::Fighting, (x, y), map, world);
+                }
+                return (landing, true);
+            }
+        }
+    }
+    (landing, false)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlayerAction {
+    /// The player acted, spending the given amount of energy.
+    TookTurn(i32),
+    DidntTakeTurn,
+    /// The player took the stairs down.
+    Descend,
+    Exit,
+}
+
+/// Move entity by the given amount. Bumping into a closed door opens it instead.
+fn move_by(id: Entity, dx: i32, dy: i32, map: &mut Map, world: &mut World,
+           messages: &mut Messages) {
+    let (x, y) = world.pos(id);
+    if is_closed_door(x + dx, y + dy, map) {
+        open_door(id, x + dx, y + dy, map, world, messages);
+    } else if is_traversable(x + dx, y + dy, map, world) {
+        world.set_pos(id, x + dx, y + dy);
+        trigger_trap(id, map, world, messages);
+    }
+}
+
+fn is_closed_door(x: i32, y: i32, map: &Map) -> bool {
+    map.get(x, y).map_or(false, |tile| tile.door == Some(DoorState::Closed))
+}
+
+fn open_door(id: Entity, x: i32, y: i32, map: &mut Map, world: &mut World, messages: &mut Messages) {
+    map[(x, y)].set_door(DoorState::Open);
+    log_message(messages, format!("{} opens the door.", world.names[id]), colors::WHITE);
+    make_noise(Noise::Door, (x, y), map, world);
+}
+
+/// Direction an entity actually goes in when trying to go in the given one: confused entities
+/// stumble in a random direction instead.
+fn stumble(id: Entity, dx: i32, dy: i32, world: &World) -> (i32, i32) {
+    if world.has_effect(id, EffectKind::Confusion) {
+        random_direction()
+    } else {
+        (dx, dy)
+    }
+}
+
+/// Move entity by the given amount, or attack whatever is there.
+/// Returns the energy cost of the action.
+fn move_by_or_attack(id: Entity, dx: i32, dy: i32, map: &mut Map,
+                     world: &mut World, messages: &mut Messages) -> i32 {
+    // The coordinates the entity is moving to/attacking.
+    let (x, y) = world.pos(id);
+    let (x, y) = (x + dx, y + dy);
+
+    // Bumping into a closed door opens it.
+    if is_closed_door(x, y, map) {
+        open_door(id, x, y, map, world, messages);
+        return OPEN_DOOR_COST;
+    }
+
+    // Attack the fighter there, if any.
+    match world.fighter_at(x, y) {
+        Some(target_id) => {
+            attack(id, target_id, map, world, messages);
+            make_noise(Noise::Fighting, (x, y), map, world);
+            ATTACK_COST
+        },
+        None => {
+            move_by(id, dx, dy, map, world, messages);
+            if id == PLAYER_ID {
+                make_noise(Noise::Footsteps, world.pos(id), map, world);
+            }
+            MOVE_COST
+        },
+    }
+}
+
+/// Unlock and open the locked door at the given position, if the player has a key.
+fn unlock_door(x: i32, y: i32, map: &mut Map, world: &mut World, inventory: &mut Vec<Object>,
+               messages: &mut Messages) -> PlayerAction {
+    match inventory.iter().position(|item| item.item == Some(Item::Key)) {
+        Some(key_id) => {
+            let key = take_one(inventory, key_id);
+            map[(x, y)].set_door(DoorState::Open);
+            log_message(messages, format!("You unlock the door with the {}.", key.name), colors::WHITE);
+            make_noise(Noise::Door, (x, y), map, world);
+            PlayerAction::TookTurn(OPEN_DOOR_COST)
+        }
+        None => {
+            log_message(messages, "The door is locked.", colors::WHITE);
+            PlayerAction::DidntTakeTurn
+        }
+    }
+}
+
+/// Close an open door next to the player, in a direction they pick.
+fn close_door(root: &mut Root, map: &mut Map, world: &mut World,
+              messages: &mut Messages) -> PlayerAction {
+    let (dx, dy) = match prompt_direction("Close in which direction?", root) {
+        Some(direction) => direction,
+        None => return PlayerAction::DidntTakeTurn,
+    };
+    let (player_x, player_y) = world.pos(PLAYER_ID);
+    let (x, y) = (player_x + dx, player_y + dy);
+
+    if map.get(x, y).map_or(true, |tile| tile.door != Some(DoorState::Open)) {
+        log_message(messages, "There is no open door there.", colors::WHITE);
+        PlayerAction::DidntTakeTurn
+    } else if !world.entities_at(x, y).is_empty() {
+        log_message(messages, "Something is in the way.", colors::WHITE);
+        PlayerAction::DidntTakeTurn
+    } else {
+        map[(x, y)].set_door(DoorState::Closed);
+        log_message(messages, "You close the door.", colors::WHITE);
+        make_noise(Noise::Door, (x, y), map, world);
+        PlayerAction::TookTurn(CLOSE_DOOR_COST)
+    }
+}
+
+/// Put out the player's torch, or light it again.
+fn toggle_torch(world: &mut World, messages: &mut Messages) -> PlayerAction {
+    if world.lights.remove(PLAYER_ID).is_some() {
+        log_message(messages, "You put out your torch, and blend into the shadows.", colors::WHITE);
+    } else {
+        world.lights.insert(PLAYER_ID, PLAYER_TORCH);
+        log_message(messages, "You light your torch.", colors::WHITE);
+    }
+    PlayerAction::TookTurn(TORCH_COST)
+}
+
+/// Search the tiles around the player, with a chance to reveal each secret door and hidden trap
+/// there.
+fn search(map: &mut Map, world: &World, messages: &mut Messages) -> PlayerAction {
+    let (player_x, player_y) = world.pos(PLAYER_ID);
+    let mut found = vec![];
+    for y in max(0, player_y - SEARCH_RADIUS)..min(MAP_HEIGHT, player_y + SEARCH_RADIUS + 1) {
+        for x in max(0, player_x - SEARCH_RADIUS)..min(MAP_WIDTH, player_x + SEARCH_RADIUS + 1) {
+            let tile = &mut map[(x, y)];
+            if tile.door == Some(DoorState::Secret) && rng().gen::<f32>() < SEARCH_CHANCE {
+                tile.set_door(DoorState::Closed);
+                found.push("a secret door".to_string());
+            }
+            if tile.has_hidden_trap() && rng().gen::<f32>() < SEARCH_CHANCE {
+                if let Some(kind) = tile.reveal_trap() {
+                    found.push(format!("a {} trap", kind.name()));
+                }
+            }
+        }
+    }
+    if found.is_empty() {
+        log_message(messages, "You search but find nothing.", colors::WHITE);
+    }
+    for thing in found {
+        log_message(messages, format!("You find {}!", thing), colors::LIGHT_BLUE);
+    }
+    PlayerAction::TookTurn(SEARCH_COST)
+}
+
+/// Give the player a chance to notice each hidden trap they can see close by.
+fn perceive_traps(map: &mut Map, world: &World, messages: &mut Messages, fov_map: &FovMap) {
+    let (player_x, player_y) = world.pos(PLAYER_ID);
+    for y in max(0, player_y - PERCEPTION_RADIUS)..min(MAP_HEIGHT, player_y + PERCEPTION_RADIUS + 1) {
+        for x in max(0, player_x - PERCEPTION_RADIUS)..min(MAP_WIDTH, player_x + PERCEPTION_RADIUS + 1) {
+            let tile = &mut map[(x, y)];
+            if tile.has_hidden_trap() && fov_map.is_in_fov(x, y) &&
+                rng().gen::<f32>() < PERCEPTION_CHANCE {
+                if let Some(kind) = tile.reveal_trap() {
+                    log_message(messages, format!("You notice a {} trap.", kind.name()), colors::LIGHT_BLUE);
+                }
+            }
+        }
+    }
+}
+
+/// Spring the trap under the given entity, if there is one. Traps reveal themselves when sprung.
+fn trigger_trap(id: Entity, map: &mut Map, world: &mut World, messages: &mut Messages) {
+    let (x, y) = world.pos(id);
+    let kind = match map[(x, y)].trap {
+        Some(trap) => trap.kind,
+        None => return,
+    };
+    map[(x, y)].reveal_trap();
+    world.emit(GameEvent::TrapSprung { id, name: world.names[id].clone(), kind }, messages);
+
+    match kind {
+        TrapKind::Dart => {
+            take_damage(id, DART_DAMAGE, DamageType::Physical, map, world, messages);
+        }
+        TrapKind::Teleport => {
+            if let Some((new_x, new_y)) = random_free_position(map, world) {
+                world.set_pos(id, new_x, new_y);
+            }
+        }
+        TrapKind::Alarm => {
+            make_noise(Noise::Alarm, (x, y), map, world);
+        }
+        TrapKind::Pit => {
+            // Falling to the level below is handled by whoever owns the level.
+            take_damage(id, PIT_DAMAGE, DamageType::Physical, map, world, messages);
+        }
+        TrapKind::PoisonGas => {
+            for victim_id in world.fighters.ids() {
+                let (victim_x, victim_y) = world.pos(victim_id);
+                let distance = (((victim_x - x).pow(2) + (victim_y - y).pow(2)) as f32).sqrt();
+                if distance <= POISON_GAS_RADIUS {
+                    apply_effect(victim_id, EffectKind::Poison, POISON_GAS_DURATION, world, messages);
+                }
+            }
+        }
+        TrapKind::Flash => {
+            for victim_id in world.fighters.ids() {
+                let (victim_x, victim_y) = world.pos(victim_id);
+                let distance = (((victim_x - x).pow(2) + (victim_y - y).pow(2)) as f32).sqrt();
+                if distance <= FLASH_RADIUS {
+                    apply_effect(victim_id, EffectKind::Blindness, FLASH_DURATION, world, messages);
+                }
+            }
+        }
+    }
+}
+
+/// Whether the entity stands on a pit, and should therefore fall to the level below.
+fn is_on_pit(id: Entity, map: &Map, world: &World) -> bool {
+    let (x, y) = world.pos(id);
+    map[(x, y)].trap.map_or(false, |trap| trap.kind == TrapKind::Pit)
+}
+
+/// A random position that can be walked to, with no trap.
+fn random_free_position(map: &Map, world: &World) -> Option<(i32, i32)> {
+    for _ in 0..1000 {
+        let x = rng().gen_range(0, MAP_WIDTH);
+        let y = rng().gen_range(0, MAP_HEIGHT);
+        if is_traversable(x, y, map, world) && map[(x, y)].trap.is_none() {
+            return Some((x, y));
+        }
+    }
+    None
+}
+
+/// Direction a movement key points to, if it is one.
+fn key_direction(key: Key) -> Option<(i32, i32)> {
+    match key {
+        Key { code: Char, printable: 'k', .. } => Some((0, -1)),
+        Key { code: Char, printable: 'j', .. } => Some((0, 1)),
+        Key { code: Char, printable: 'h', .. } => Some((-1, 0)),
+        Key { code: Char, printable: 'l', .. } => Some((1, 0)),
+        Key { code: Char, printable: 'y', .. } => Some((-1, -1)),
+        Key { code: Char, printable: 'u', .. } => Some((1, -1)),
+        Key { code: Char, printable: 'b', .. } => Some((-1, 1)),
+        Key { code: Char, printable: 'n', .. } => Some((1, 1)),
+        _ => None,
+    }
+}
+
+fn random_direction() -> (i32, i32) {
+    const DIRECTIONS: [(i32, i32); 8] =
+        [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+    DIRECTIONS[rng().gen_range(0, DIRECTIONS.len())]
+}
+
+fn move_towards(id: Entity, target_x: i32, target_y: i32, map: &mut Map, world: &mut World,
+                messages: &mut Messages) {
+    // Vector from entity to target.
+    let (x, y) = world.pos(id);
+    let dx = target_x - x;
+    let dy = target_y - y;
+    let dist = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
+
+    // Normalise the vector.
+    let dx = (dx as f32 / dist).round() as i32;
+    let dy = (dy as f32 / dist).round() as i32;
+
+    move_by(id, dx, dy, map, world, messages);
+}
+
+/// Take one step along the shortest path to the target, or straight towards it if there is no
+/// such path.
+fn move_along_path(id: Entity, target_x: i32, target_y: i32, map: &mut Map, world: &mut World,
+                   messages: &mut Messages) {
+    let (x, y) = world.pos(id);
+    match find_path((x, y), (target_x, target_y), map, world).and_then(|path| path.first().cloned()) {
+        Some((step_x, step_y)) => move_by(id, step_x - x, step_y - y, map, world, messages),
+        None => move_towards(id, target_x, target_y, map, world, messages),
+    }
+}
+
+/// Find a path between two positions with the A* algorithm, moving in all eight directions.
+///
+/// Untraversable tiles, blocking entities and revealed traps are avoided, except on the goal
+/// itself. Closed doors are not, since they open when bumped into. Returns the positions to step
+/// on, excluding the start, or None if there is no path of at most MAX_PATH_LENGTH steps.
+fn find_path(start: (i32, i32), goal: (i32, i32), map: &Map, world: &World)
+    -> Option<Vec<(i32, i32)>> {
+    let can_step_on = |(x, y): (i32, i32)| {
+        if (x, y) == goal {
+            return true;
+        }
+        map.get(x, y).map_or(false, |tile| {
+            (tile.traversable || tile.door == Some(DoorState::Closed)) &&
+                world.blocking_at(x, y).is_none() &&
+                tile.trap.map_or(true, |trap| trap.hidden)
+        })
+    };
+    // Diagonal moves cost the same as straight ones, hence the Chebyshev distance.
+    let estimate = |(x, y): (i32, i32)| max((goal.0 - x).abs(), (goal.1 - y).abs());
+
+    let mut open = BinaryHeap::new();
+    let mut came_from = HashMap::new();
+    let mut cost_so_far = HashMap::new();
+    open.push(Reverse((estimate(start), 0, start)));
+    cost_so_far.insert(start, 0);
+
+    while let Some(Reverse((_, cost, position))) = open.pop() {
+        if position == goal {
+            let mut path = vec![goal];
+            let mut current = goal;
+            while let Some(&previous) = came_from.get(&current) {
+                if previous == start {
+                    break;
+                }
+                path.push(previous);
+                current = previous;
+            }
+            path.reverse();
+            return Some(path);
+        }
+        if cost > cost_so_far[&position] || cost >= MAX_PATH_LENGTH {
+            continue;
+        }
+        for next in map.neighbours(position.0, position.1) {
+            if !can_step_on(next) {
+                continue;
+            }
+            let next_cost = cost + 1;
+            if cost_so_far.get(&next).map_or(true, |&c| next_cost < c) {
+                cost_so_far.insert(next, next_cost);
+                came_from.insert(next, position);
+                open.push(Reverse((next_cost + estimate(next), next_cost, next)));
+            }
+        }
+    }
+    None
+}
+
+/// Returns the energy cost of the action the monster took.
+fn ai_take_turn(monster_id: Entity, map: &mut Map, world: &mut World, messages: &mut Messages,
+                fov_map: &FovMap) -> i32 {
+    // Confused monsters stumble around, attacking whatever they bump into.
+    if world.has_effect(monster_id, EffectKind::Confusion) {
+        let (dx, dy) = random_direction();
+        return move_by_or_attack(monster_id, dx, dy, map, world, messages);
+    }
+
+    let mut ai = match world.ais.get(monster_id) {
+        Some(&ai) => ai,
+        None => return WAIT_COST,
+    };
+    let (monster_x, monster_y) = world.pos(monster_id);
+    let visible = fov_map.is_in_fov(monster_x, monster_y);
+    let name = world.names[monster_id].clone();
+
+    // Tell the player what the monster reacted to, if they can see it.
+    if let Some((noise, previously)) = ai.heard.take() {
+        if visible && previously == Alertness::Asleep {
+            log_message(messages, format!("The {} wakes up, hearing {}!", name, noise.description()),
+                        colors::ORANGE);
+        } else if visible {
+            log_message(messages, format!("The {} hears {} and comes to look.", name, noise.description()),
+                        colors::ORANGE);
+        }
+    }
+
+    // Hunt the player while they are in sight, remembering where they were last seen.
+    let sees_player = can_see(monster_id, PLAYER_ID, map, world, fov_map);
+    if sees_player {
+        if ai.alertness != Alertness::Hunting && visible {
+            log_message(messages, format!("The {} notices you!", name), colors::DARK_RED);
+        }
+        ai.alertness = Alertness::Hunting;
+        ai.destination = Some(world.pos(PLAYER_ID));
+    }
+    world.ais[monster_id] = ai;
+
+    match ai.alertness {
+        Alertness::Asleep => WAIT_COST,
+        Alertness::Hunting if sees_player => {
+            if world.distance(monster_id, PLAYER_ID) >= 2.0 {
+                // Move towards the player.
+                let (player_x, player_y) = world.pos(PLAYER_ID);
+                move_along_path(monster_id, player_x, player_y, map, world, messages);
+                MOVE_COST
+            } else {
+                attack(monster_id, PLAYER_ID, map, world, messages);
+                make_noise(Noise::Fighting, world.pos(PLAYER_ID), map, world);
+                ATTACK_COST
+            }
+        }
+        Alertness::Hunting | Alertness::Investigating => match ai.destination {
+            // Head to the destination, and go back to wandering once there.
+            Some((x, y)) if (monster_x, monster_y) != (x, y) => {
+                move_along_path(monster_id, x, y, map, world, messages);
+                MOVE_COST
+            }
+            _ => {
+                if let Some(ai) = world.ais.get_mut(monster_id) {
+                    ai.alertness = Alertness::Wandering;
+                    ai.destination = None;
+                }
+                WAIT_COST
+            }
+        },
+        Alertness::Wandering => {
+            if rng().gen::<f32>() < WANDER_CHANCE {
+                let (dx, dy) = random_direction();
+                move_by(monster_id, dx, dy, map, world, messages);
+                MOVE_COST
+            } else {
+                WAIT_COST
+            }
+        }
+    }
+}
+
+/// Whether a monster notices a target: it must be within the monster's sight radius, with nothing
+/// opaque in between, and lit unless the monster sees in the dark. Sleeping monsters see nothing.
+fn can_see(viewer: Entity, target: Entity, map: &Map, world: &World, fov_map: &FovMap) -> bool {
+    let ai = world.ais.get(viewer);
+    if ai.map_or(false, |ai| ai.alertness == Alertness::Asleep) ||
+        world.distance(viewer, target) > world.sight_radius(viewer) as f32 {
+        return false;
+    }
+    let (target_x, target_y) = world.pos(target);
+    let dark_vision = ai.map_or(false, |ai| ai.dark_vision);
+    if !dark_vision && !fov_map.is_lit(target_x, target_y) {
+        return false;
+    }
+    in_line_of_sight(world.pos(viewer), (target_x, target_y), map)
+}
+
+/// Whether nothing opaque stands between two positions.
+fn in_line_of_sight(from: (i32, i32), to: (i32, i32), map: &Map) -> bool {
+    let mut path = line(from, to);
+    path.pop();
+    path.iter().all(|&(x, y)| map[(x, y)].transparent)
+}
+
+/// Play turns until the player has accumulated enough energy to act.
+///
+/// Every turn, all actors gain energy according to their speed. Monsters then act for as long as
+/// they have enough energy, so that fast ones may act several times per turn and slow ones only
+/// every few turns.
+fn play_until_player_turn(map: &mut Map, world: &mut World, messages: &mut Messages,
+                          fov_map: &FovMap) {
+    while world.is_alive(PLAYER_ID) && world.actors[PLAYER_ID].energy < ENERGY_TO_ACT {
+        messages.turn += 1;
+
+        tick_effects(map, world, messages);
+
+        for id in world.actors.ids() {
+            if id != PLAYER_ID && !world.ais.contains(id) {
+                continue;
+            }
+            let speed = world.speed(id);
+            world.actors[id].energy += speed;
+
+            // The player acts on their own, when a key is pressed.
+            if id == PLAYER_ID {
+                continue;
+            }
+            while world.ais.contains(id) && world.is_alive(PLAYER_ID) &&
+                world.actors[id].energy >= ENERGY_TO_ACT {
+                let cost = ai_take_turn(id, map, world, messages, fov_map);
+                world.actors[id].energy -= cost;
+            }
+        }
+
+        // Monsters that fell into a pit are gone to the level below.
+        for id in world.ais.ids() {
+            if is_on_pit(id, map, world) {
+                world.despawn(id);
+            }
+        }
+
+        if world.is_alive(PLAYER_ID) {
+            perceive_traps(map, world, messages, fov_map);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Tile {
+    explored: bool,
+    /// How an item on the tile looked the last time the player saw it, if there was one.
+    seen_item: Option<Renderable>,
+    traversable: bool,
+    transparent: bool,
+    door: Option<DoorState>,
+    trap: Option<Trap>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Trap {
+    kind: TrapKind,
+    /// Hidden traps are not displayed, and not avoided by monsters.
+    hidden: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TrapKind {
+    Dart,
+    Teleport,
+    /// Calls the monsters around.
+    Alarm,
+    /// Drops whoever falls in to the level below.
+    Pit,
+    PoisonGas,
+    /// Blinds whoever is close enough.
+    Flash,
+}
+
+impl TrapKind {
+    fn name(self) -> &'static str {
+        match self {
+            TrapKind::Dart => "dart",
+            TrapKind::Teleport => "teleport",
+            TrapKind::Alarm => "alarm",
+            TrapKind::Pit => "pit",
+            TrapKind::PoisonGas => "poison gas",
+            TrapKind::Flash => "flash",
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            TrapKind::Dart => colors::LIGHT_GREY,
+            TrapKind::Teleport => colors::LIGHT_MAGENTA,
+            TrapKind::Alarm => colors::ORANGE,
+            TrapKind::Pit => colors::DARK_SEPIA,
+            TrapKind::PoisonGas => colors::GREEN,
+            TrapKind::Flash => colors::LIGHT_YELLOW,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DoorState {
+    Open,
+    Closed,
+    /// Closed, and needs a key to be opened.
+    Locked,
+    /// Looks like a wall until found by searching.
+    Secret,
+}
+
+impl Tile {
+    pub fn empty() -> Self {
+        Tile { explored: false, seen_item: None, traversable: true, transparent: true, door: None, trap: None }
+    }
+
+    pub fn wall() -> Self {
+        Tile { explored: false, seen_item: None, traversable: false, transparent: false, door: None, trap: None }
+    }
+
+    pub fn door(state: DoorState) -> Self {
+        let mut tile = Tile::wall();
+        tile.set_door(state);
+        tile
+    }
+
+    /// Turn the tile into a door in the given state. Only open doors can be seen through and
+    /// crossed.
+    pub fn set_door(&mut self, state: DoorState) {
+        self.door = Some(state);
+        self.traversable = state == DoorState::Open;
+        self.transparent = state == DoorState::Open;
+    }
+
+    pub fn has_hidden_trap(&self) -> bool {
+        self.trap.map_or(false, |trap| trap.hidden)
+    }
+
+    /// Reveal the trap on the tile. Returns its kind if it was hidden until now.
+    pub fn reveal_trap(&mut self) -> Option<TrapKind> {
+        match self.trap {
+            Some(Trap { kind, hidden: true }) => {
+                self.trap = Some(Trap { kind, hidden: false });
+                Some(kind)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the tile can be walked on, possibly after opening or finding its door.
+    pub fn is_passable(&self) -> bool {
+        self.traversable || self.door.is_some()
+    }
+
+    /// Whether the tile is displayed as a wall.
+    pub fn is_wall(&self) -> bool {
+        !self.transparent && self.door.map_or(true, |door| door == DoorState::Secret)
+    }
+
+    /// Character drawn on the tile, if any.
+    pub fn glyph(&self) -> Option<char> {
+        match self.door {
+            Some(DoorState::Open) => Some('\''),
+            Some(DoorState::Closed) | Some(DoorState::Locked) => Some('+'),
+            Some(DoorState::Secret) | None => None,
+        }
+    }
+}
+
+/// A value for every tile of a rectangular area, stored row by row. Positions outside of it can
+/// be asked for with `get` and `get_mut`, while indexing with them panics.
+#[derive(Clone, Debug)]
+struct Grid<T> {
+    width: i32,
+    height: i32,
+    cells: Vec<T>,
+}
+
+impl<T: Clone> Grid<T> {
+    pub fn new(width: i32, height: i32, value: T) -> Self {
+        Grid { width, height, cells: vec![value; (width * height) as usize] }
+    }
+}
+
+impl<T> Grid<T> {
+    pub fn in_bounds(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && x < self.width && y < self.height
+    }
+
+    fn offset(&self, x: i32, y: i32) -> Option<usize> {
+        if self.in_bounds(x, y) {
+            Some((y * self.width + x) as usize)
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> Option<&T> {
+        let offset = self.offset(x, y)?;
+        Some(&self.cells[offset])
+    }
+
+    pub fn get_mut(&mut self, x: i32, y: i32) -> Option<&mut T> {
+        let offset = self.offset(x, y)?;
+        Some(&mut self.cells[offset])
+    }
+
+    /// Positions around the given one in all eight directions, leaving out those outside the grid.
+    pub fn neighbours(&self, x: i32, y: i32) -> impl Iterator<Item = (i32, i32)> + '_ {
+        (-1..2).flat_map(move |dy| (-1..2).map(move |dx| (x + dx, y + dy)))
+            .filter(move |&(next_x, next_y)| (next_x, next_y) != (x, y) && self.in_bounds(next_x, next_y))
+    }
+
+    /// Positions above, below, left and right of the given one, leaving out those outside the
+    /// grid.
+    pub fn orthogonal_neighbours(&self, x: i32, y: i32) -> impl Iterator<Item = (i32, i32)> + '_ {
+        [(1, 0), (-1, 0), (0, 1), (0, -1)].iter()
+            .map(move |&(dx, dy)| (x + dx, y + dy))
+            .filter(move |&(next_x, next_y)| self.in_bounds(next_x, next_y))
+    }
+
+    /// Every value with its position, row by row.
+    pub fn iter(&self) -> impl Iterator<Item = ((i32, i32), &T)> + '_ {
+        let width = self.width;
+        self.cells.iter().enumerate().map(move |(offset, value)| {
+            let offset = offset as i32;
+            ((offset % width, offset / width), value)
+        })
+    }
+}
+
+impl<T> Index<(i32, i32)> for Grid<T> {
+    type Output = T;
+
+    fn index(&self, (x, y): (i32, i32)) -> &T {
+        self.get(x, y).expect("Position outside of the grid")
+    }
+}
+
+impl<T> IndexMut<(i32, i32)> for Grid<T> {
+    fn index_mut(&mut self, (x, y): (i32, i32)) -> &mut T {
+        self.get_mut(x, y).expect("Position outside of the grid")
+    }
+}
+
+type Map = Grid<Tile>;
+
+/// Part of the map shown on screen, given by the map position of its top left corner.
+#[derive(Clone, Copy, Debug, Default)]
+struct Camera {
+    x: i32,
+    y: i32,
+}
+
+impl Camera {
+    /// Center the camera on the given map position, without going past the edges of the map.
+    pub fn follow(&mut self, (x, y): (i32, i32)) {
+        self.x = (x - CAMERA_WIDTH / 2).clamp(0, MAP_WIDTH - CAMERA_WIDTH);
+        self.y = (y - CAMERA_HEIGHT / 2).clamp(0, MAP_HEIGHT - CAMERA_HEIGHT);
+    }
+
+    /// Position on screen of a map position, if the camera shows it.
+    pub fn to_screen(self, x: i32, y: i32) -> Option<(i32, i32)> {
+        let (screen_x, screen_y) = (x - self.x, y - self.y);
+        if screen_x < 0 || screen_y < 0 || screen_x >= CAMERA_WIDTH || screen_y >= CAMERA_HEIGHT {
+            None
+        } else {
+            Some((screen_x, screen_y))
+        }
+    }
+
+    /// Map position shown at a position on screen, if any.
+    pub fn to_map(self, screen_x: i32, screen_y: i32) -> Option<(i32, i32)> {
+        let (x, y) = (screen_x + self.x, screen_y + self.y);
+        if screen_x < 0 || screen_y < 0 || screen_x >= CAMERA_WIDTH || screen_y >= CAMERA_HEIGHT ||
+            x >= MAP_WIDTH || y >= MAP_HEIGHT {
+            None
+        } else {
+            Some((x, y))
+        }
+    }
+}
+
+/// The consoles the game is drawn on, and what part of the game they show.
+struct Screen {
+    root: Root,
+    /// The part of the map in view of the camera.
+    con: Offscreen,
+    panel: Offscreen,
+    minimap: Offscreen,
+    camera: Camera,
+    show_minimap: bool,
+    mouse: Mouse,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Rect {
+    x1: i32,
+    y1: i32,
+    x2: i32,
+    y2: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
+        Rect { x1: x, y1: y, x2: x + w, y2: y + h }
+    }
+    pub fn center(&self) -> (i32, i32) {
+        ((self.x1 + self.x2) / 2, (self.y1 + self.y2) / 2)
+    }
+    pub fn intersects_with(&self, other: &Rect) -> bool {
+        let x_intersects: bool = self.x2 >= other.x1 && self.x1 <= other.x2;
+        let y_intersects: bool = self.y2 >= other.y1 && self.y1 <= other.y2;
+        x_intersects && y_intersects
+    }
+}
+
+/// A single entry of the message log.
+struct Message {
+    text: String,
+    color: Color,
+    /// Turn on which the message was first logged.
+    turn: u32,
+    /// Number of consecutive times the message was logged.
+    count: u32,
+}
+
+impl Message {
+    /// The message text, followed by the repeat count if it was logged several times in a row.
+    pub fn display_text(&self) -> String {
+        if self.count > 1 {
+            format!("{} x{}", self.text, self.count)
+        } else {
+            self.text.clone()
+        }
+    }
+}
+
+/// The message log: the panel shows the last few entries, the history screen shows all of them.
+struct Messages {
+    /// All the messages logged so far, oldest first, capped to MSG_HISTORY_SIZE entries.
+    history: VecDeque<Message>,
+    /// Turn number stamped onto new messages.
+    turn: u32,
+}
+
+impl Messages {
+    pub fn new() -> Self {
+        Messages { history: VecDeque::new(), turn: 0 }
+    }
+}
+
+/// Something which happened in the game. The game logic only emits events, and everything which
+/// merely reacts to them subscribes to them, so that headless runs can check what happened
+/// without reading the message log.
+#[derive(Clone, Debug, PartialEq)]
+enum GameEvent {
+    /// An attack, which dealt no damage if it missed or had no effect. The damage is what the
+    /// target takes once their affinity to the type of damage is applied.
+    Attacked {
+        attacker: Entity,
+        target: Entity,
+        attacker_name: String,
+        target_name: String,
+        /// What the attacker shot or threw, for ranged attacks.
+        projectile: Option<String>,
+        hit: HitRoll,
+        damage: i32,
+    },
+    /// A spell struck a fighter, before the damage was taken.
+    SpellHit { spell: Item, target: Entity, target_name: String, damage: i32 },
+    /// Someone stepped on a trap, which is about to go off.
+    TrapSprung { id: Entity, name: String, kind: TrapKind },
+    /// A fighter got hurt, losing the given hit points once their affinity to the type of damage
+    /// was applied, and has the given ones left.
+    Damaged {
+        target: Entity,
+        name: String,
+        position: (i32, i32),
+        kind: DamageType,
+        affinity: Affinity,
+        damage: i32,
+        hp: i32,
+    },
+    Died { id: Entity, name: String },
+    /// A dying monster dropped the given items.
+    LootDropped { id: Entity, name: String, items: Vec<String> },
+    PickedUp { item: Item, name: String },
+    /// The player tried to pick up an item with no room left in the inventory.
+    InventoryFull { name: String },
+    ItemUsed { item: Item, name: String },
+    LevelChanged { level: u32 },
+}
+
+/// Something reacting to the game events. Subscribers can tell the player about them through the
+/// message log.
+trait Subscriber {
+    fn notify(&mut self, event: &GameEvent, messages: &mut Messages);
+}
+
+/// The subscribers, notified in this order as soon as an event is emitted.
+struct EventBus {
+    message_log: MessageLog,
+    statistics: Statistics,
+    achievements: Achievements,
+    replay: Replay,
+    ui: UiEffects,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus {
+            message_log: MessageLog,
+            statistics: Statistics { deepest_level: 1, ..Default::default() },
+            achievements: Achievements::default(),
+            replay: Replay::default(),
+            ui: UiEffects::default(),
+        }
+    }
+
+    pub fn publish(&mut self, event: &GameEvent, messages: &mut Messages) {
+        let mut subscribers: [&mut dyn Subscriber; 5] = [
+            &mut self.message_log,
+            &mut self.statistics,
+            &mut self.achievements,
+            &mut self.replay,
+            &mut self.ui,
+        ];
+        for subscriber in subscribers.iter_mut() {
+            subscriber.notify(event, messages);
+        }
+    }
+}
+
+/// Describes the events to the player.
+struct MessageLog;
+
+impl Subscriber for MessageLog {
+    fn notify(&mut self, event: &GameEvent, messages: &mut Messages) {
+        match event {
+            // Ranged attacks are told from the point of view of the projectile.
+            GameEvent::Attacked { target_name, projectile: Some(projectile), hit: HitRoll::Miss, .. } => {
+                log_message(messages, format!("The {} misses {}.", projectile, target_name), colors::WHITE)
+            }
+            GameEvent::Attacked { target_name, projectile: Some(projectile), hit: HitRoll::Critical, damage, .. }
+                if *damage > 0 => {
+                log_message(messages,
+                            format!("The {} critically hits {} for {} hit points!", projectile, target_name, damage),
+                            colors::LIGHT_YELLOW)
+            }
+            GameEvent::Attacked { target_name, projectile: Some(projectile), damage, .. } if *damage > 0 => {
+                log_message(messages,
+                            format!("The {} hits {} for {} hit points!", projectile, target_name, damage),
+                            colors::WHITE)
+            }
+            GameEvent::Attacked { target_name, projectile: Some(projectile), .. } => {
+                log_message(messages,
+                            format!("The {} hits {} but it has no effect!", projectile, target_name),
+                            colors::WHITE)
+            }
+            GameEvent::Attacked { attacker_name, target_name, hit: HitRoll::Miss, .. } => {
+                log_message(messages, format!("{} attacks {} but misses.", attacker_name, target_name), colors::WHITE)
+            }
+            GameEvent::Attacked { attacker_name, target_name, hit: HitRoll::Critical, damage, .. } if *damage > 0 => {
+                log_message(messages,
+                            format!("{} critically hits {} for {} hit points!", attacker_name, target_name, damage),
+                            colors::LIGHT_YELLOW)
+            }
+            GameEvent::Attacked { attacker_name, target_name, damage, .. } if *damage > 0 => {
+                log_message(messages,
+                            format!("{} attacks {} for {} hit points!", attacker_name, target_name, damage),
+                            colors::WHITE)
+            }
+            GameEvent::Attacked { attacker_name, target_name, .. } => {
+                log_message(messages,
+                            format!("{} attacks {} but it has no effect!", attacker_name, target_name),
+                            colors::WHITE)
+            }
+            GameEvent::Died { id, .. } if *id == PLAYER_ID => log_message(messages, "You died!", colors::DARK_RED),
+            GameEvent::Died { name, .. } => log_message(messages, format!("{} is dead!", name), colors::ORANGE),
+            GameEvent::LootDropped { name, items, .. } => {
+                log_message(messages, format!("{} drops {}.", name, items.join(", ")), colors::LIGHT_GREY)
+            }
+            GameEvent::SpellHit { spell: Item::Fireball, target_name, damage, .. } => {
+                log_message(messages,
+                            format!("{} is caught in the blast for {} hit points!", target_name, damage),
+                            colors::ORANGE)
+            }
+            GameEvent::SpellHit { target_name, damage, .. } => {
+                log_message(messages,
+                            format!("A lightning bolt strikes {} for {} hit points!", target_name, damage),
+                            colors::LIGHT_BLUE)
+            }
+            GameEvent::TrapSprung { name, kind, .. } => {
+                let (text, color) = match kind {
+                    TrapKind::Dart => (format!("A dart shoots out at {}!", name), colors::ORANGE),
+                    TrapKind::Teleport => (format!("{} is teleported away!", name), colors::LIGHT_MAGENTA),
+                    TrapKind::Alarm => ("A loud alarm goes off!".to_string(), colors::ORANGE),
+                    TrapKind::Pit => (format!("{} falls into a pit!", name), colors::ORANGE),
+                    TrapKind::PoisonGas => ("A cloud of poison gas bursts out!".to_string(), colors::GREEN),
+                    TrapKind::Flash => ("A blinding flash of light goes off!".to_string(), colors::LIGHT_YELLOW),
+                };
+                log_message(messages, text, color)
+            }
+            GameEvent::PickedUp { name, .. } => {
+                log_message(messages, format!("You picked up a {}.", name), colors::RED)
+            }
+            GameEvent::InventoryFull { name } => {
+                log_message(messages, format!("Your inventory is full, cannot pick up {}.", name), colors::RED)
+            }
+            GameEvent::LevelChanged { level } => {
+                log_message(messages, format!("You reach dungeon level {}.", level), colors::LIGHT_VIOLET)
+            }
+            GameEvent::Damaged { name, kind, affinity, .. } if *affinity != Affinity::Normal => {
+                log_message(messages,
+                            format!("{} is {} to {}.", name, affinity.description(), kind.name()),
+                            colors::LIGHT_GREY)
+            }
+            GameEvent::Damaged { .. } | GameEvent::ItemUsed { .. } => {}
+        }
+    }
+}
+
+/// Totals over the whole game, shown when it ends.
+#[derive(Debug, Default)]
+struct Statistics {
+    monsters_killed: u32,
+    /// Damage taken by monsters, whatever hurt them.
+    damage_dealt: i32,
+    damage_taken: i32,
+    items_picked_up: u32,
+    items_used: u32,
+    deepest_level: u32,
+}
+
+impl Subscriber for Statistics {
+    fn notify(&mut self, event: &GameEvent, _messages: &mut Messages) {
+        match event {
+            GameEvent::Damaged { target, damage, .. } if *target == PLAYER_ID => self.damage_taken += damage,
+            GameEvent::Damaged { damage, .. } => self.damage_dealt += damage,
+            GameEvent::Died { id, .. } if *id != PLAYER_ID => self.monsters_killed += 1,
+            GameEvent::PickedUp { .. } => self.items_picked_up += 1,
+            GameEvent::ItemUsed { .. } => self.items_used += 1,
+            GameEvent::LevelChanged { level } => self.deepest_level = max(self.deepest_level, *level),
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Monsters killed: {}", self.monsters_killed)?;
+        writeln!(f, "Damage dealt: {}", self.damage_dealt)?;
+        writeln!(f, "Damage taken: {}", self.damage_taken)?;
+        writeln!(f, "Items picked up: {}", self.items_picked_up)?;
+        writeln!(f, "Items used: {}", self.items_used)?;
+        writeln!(f, "Deepest level: {}", self.deepest_level)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Achievement {
+    FirstBlood,
+    Slayer,
+    Hoarder,
+    DeepDelver,
+}
+
+impl Achievement {
+    pub fn name(self) -> &'static str {
+        match self {
+            Achievement::FirstBlood => "First Blood",
+            Achievement::Slayer => "Slayer",
+            Achievement::Hoarder => "Hoarder",
+            Achievement::DeepDelver => "Deep Delver",
+        }
+    }
+
+    pub fn description(self) -> String {
+        match self {
+            Achievement::FirstBlood => "kill a monster".to_string(),
+            Achievement::Slayer => format!("kill {} monsters", SLAYER_KILLS),
+            Achievement::Hoarder => format!("pick up {} items", HOARDER_PICK_UPS),
+            Achievement::DeepDelver => format!("reach dungeon level {}", DEEP_DELVER_LEVEL),
+        }
+    }
+}
+
+/// Achievements unlocked so far, in the order they were, and the counts they depend on.
+#[derive(Debug, Default)]
+struct Achievements {
+    unlocked: Vec<Achievement>,
+    kills: u32,
+    pick_ups: u32,
+}
+
+impl Achievements {
+    fn unlock(&mut self, achievement: Achievement, messages: &mut Messages) {
+        if !self.unlocked.contains(&achievement) {
+            self.unlocked.push(achievement);
+            log_message(messages, format!("Achievement unlocked: {}!", achievement.name()), colors::YELLOW);
+        }
+    }
+}
+
+impl Subscriber for Achievements {
+    fn notify(&mut self, event: &GameEvent, messages: &mut Messages) {
+        match event {
+            GameEvent::Died { id, .. } if *id != PLAYER_ID => {
+                self.kills += 1;
+                self.unlock(Achievement::FirstBlood, messages);
+                if self.kills >= SLAYER_KILLS {
+                    self.unlock(Achievement::Slayer, messages);
+                }
+            }
+            GameEvent::PickedUp { .. } => {
+                self.pick_ups += 1;
+                if self.pick_ups >= HOARDER_PICK_UPS {
+                    self.unlock(Achievement::Hoarder, messages);
+                }
+            }
+            GameEvent::LevelChanged { level } if *level >= DEEP_DELVER_LEVEL => {
+                self.unlock(Achievement::DeepDelver, messages)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Every event of the game, with the turn it happened on.
+#[derive(Debug, Default)]
+struct Replay {
+    events: Vec<(u32, GameEvent)>,
+}
+
+impl Replay {
+    /// Write the events to a file, one per line.
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let text: String = self.events.iter().map(|(turn, event)| format!("{} {:?}\n", turn, event)).collect();
+        std::fs::write(path, text)
+    }
+}
+
+impl Subscriber for Replay {
+    fn notify(&mut self, event: &GameEvent, messages: &mut Messages) {
+        self.events.push((messages.turn, event.clone()));
+    }
+}
+
+/// Feedback drawn over the map: the tiles where something got hurt since the player last acted.
+#[derive(Debug, Default)]
+struct UiEffects {
+    flashes: Vec<(i32, i32)>,
+}
+
+impl Subscriber for UiEffects {
+    fn notify(&mut self, event: &GameEvent, _messages: &mut Messages) {
+        if let GameEvent::Damaged { position, damage, .. } = event {
+            if *damage > 0 {
+                self.flashes.push(*position);
+            }
+        }
+    }
+}
+
+const GAME_USAGE: &str = "usage: rust-roguelike [--replay FILE]";
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("mapgen") {
+        mapgen(&args[2..]);
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("bench") {
+        bench(&args[2..]);
+        return;
+    }
+
+    // The game's events are only saved to a replay file when asked to.
+    let mut replay_file = None;
+    let mut game_args = args[1..].iter();
+    while let Some(arg) = game_args.next() {
+        match (arg.as_str(), game_args.next()) {
+            ("--replay", Some(file)) => replay_file = Some(file.clone()),
+            _ => {
+                eprintln!("{}", GAME_USAGE);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    println!("Hello, world!");
+
+    let root = Root::initializer()
+    .font("arial10x10.png", FontLayout::Tcod)
+    .font_type(FontType::Greyscale)
+    .size(SCREEN_WIDTH, SCREEN_HEIGHT)
+    .title("Rust/libtcod tutorial")
+    .init();
+
+    let mut screen = Screen {
+        root,
+        con: Offscreen::new(CAMERA_WIDTH, CAMERA_HEIGHT),
+        panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),
+        minimap: Offscreen::new(MINIMAP_WIDTH, MINIMAP_HEIGHT),
+        camera: Camera::default(),
+        show_minimap: false,
+        mouse: Default::default(),
+    };
+    let mut messages = Messages::new();
+
+    tcod::system::set_fps(LIMIT_FPS);
+
+    let mut dungeon_level = 1;
+    let mut player = Object::new(0, 0, '@', "player", colors::WHITE, false);
+    // The player gets to act first.
+    player.energy = ENERGY_TO_ACT;
+    player.fighter = Some(Fighter { max_hp: 30, hp: 30, accuracy: 2, evasion: 2, damage: Dice::new(1, 4, 3),
+                                    damage_type: DamageType::Physical, armour: 2, resistances: Resistances::none(),
+                                    worn: None, on_death: DeathCallback::Player, attack_effect: None });
+    player.light = Some(PLAYER_TORCH);
+
+    // let npc = Object::new(player.x - 1, player.y -3, '@', colors::YELLOW);
+    // The player is the first entity, so that its handle is PLAYER_ID.
+    let mut world = World::new();
+    world.spawn(player);
+    let (mut map, (player_x, player_y)) = make_map(&mut world, dungeon_level);
+    world.set_pos(PLAYER_ID, player_x, player_y);
+
+    // Fill the field-of-view map
+    let mut fov_map = FovMap::new(MAP_WIDTH, MAP_HEIGHT);
+    update_fov_map(&map, &mut fov_map);
+
+    // Player position and sight radius the FOV was last computed for.
+    let mut prev_player_view = (-1, -1, -1);
+
+    // A warm welcoming message!
+    log_message(
+        &mut messages,
+        "Welcome stranger! Prepare to perish in the Tombs of the Ancient Kings.",
+        colors::RED,
+    );
+
+    let mut key = Default::default();
+
+    let mut inventory = vec![];
+
+    while !screen.root.window_closed() {
+        // clear the screen of the previous frame
+        screen.con.clear();
+
+        screen.con.set_default_foreground(colors::WHITE);
+
+        match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
+            Some((_, Event::Mouse(m))) => screen.mouse = m,
+            Some((_, Event::Key(k))) => key = k,
+            _ => key = Default::default(),
+        }
+
+        let (player_x, player_y) = world.pos(PLAYER_ID);
+        let player_view = (player_x, player_y, world.sight_radius(PLAYER_ID));
+        let fov_recompute = prev_player_view != player_view;
+        screen.camera.follow((player_x, player_y));
+        render_all(&mut screen, &world, &messages, &mut map, &mut fov_map, fov_recompute);
+
+        screen.root.flush();
+
+        prev_player_view = player_view;
+
+        // Hits stay highlighted until the player does something.
+        if key.code != NoKey {
+            world.events.ui.flashes.clear();
+        }
+
+        // Handle keys and exit if needed
+        let player_action = handle_keys(key, &mut screen, &mut map, &mut world, &mut inventory,
+                                        &mut messages, &fov_map);
+        if player_action == PlayerAction::Exit {
+            break;
+        }
+        if let PlayerAction::TookTurn(cost) = player_action {
+            world.actors[PLAYER_ID].energy -= cost;
+        }
+
+        // Taking the stairs or falling into a pit takes the player one level down.
+        let fell = world.is_alive(PLAYER_ID) && is_on_pit(PLAYER_ID, &map, &world);
+        if player_action == PlayerAction::Descend || fell {
+            dungeon_level += 1;
+            next_level(dungeon_level, &mut map, &mut world);
+            world.emit(GameEvent::LevelChanged { level: dungeon_level }, &mut messages);
+            update_fov_map(&map, &mut fov_map);
+            let (player_x, player_y) = world.pos(PLAYER_ID);
+            fov_map.compute_fov(player_x, player_y, world.sight_radius(PLAYER_ID), FOV_LIGHT_WALLS, FOV_ALGO);
+            fov_map.compute_lighting(&world);
+            prev_player_view = (-1, -1, -1);
+        }
+
+        if let PlayerAction::TookTurn(_) = player_action {
+            play_until_player_turn(&mut map, &mut world, &mut messages, &fov_map);
+
+            // The map may have changed (e.g. doors were opened), so the FOV has to follow.
+            update_fov_map(&map, &mut fov_map);
+            prev_player_view = (-1, -1, -1);
+        }
+    }
+
+    print!("{}", world.events.statistics);
+    for achievement in &world.events.achievements.unlocked {
+        println!("Achievement: {} ({})", achievement.name(), achievement.description());
+    }
+    if let Some(replay_file) = replay_file {
+        if let Err(error) = world.events.replay.save(&replay_file) {
+            println!("Could not save the replay: {}", error);
+        }
+    }
+}
+
+/// Replace the map with a new one for the given level. Only the player is kept.
+fn next_level(dungeon_level: u32, map: &mut Map, world: &mut World) {
+    for id in world.entities() {
+        if id != PLAYER_ID {
+            world.despawn(id);
+        }
+    }
+
+    let (new_map, (player_x, player_y)) = make_map(world, dungeon_level);
+    *map = new_map;
+    world.set_pos(PLAYER_ID, player_x, player_y);
+}
+
+/// What the player can see: the tiles in their field of view which are lit enough.
+struct FovMap {
+    width: i32,
+    height: i32,
+    /// Field of view of the player, regardless of light.
+    sight: TcodMap,
+    /// Where the field of view was computed from. The player always knows their own tile, even
+    /// in the dark.
+    viewer: (i32, i32),
+    /// Field of view of the light source being computed.
+    light_fov: TcodMap,
+    /// Colour of the light received by each tile.
+    light: Grid<Color>,
+}
+
+impl FovMap {
+    pub fn new(width: i32, height: i32) -> Self {
+        FovMap {
+            width,
+            height,
+            sight: TcodMap::new(width, height),
+            viewer: (-1, -1),
+            light_fov: TcodMap::new(width, height),
+            light: Grid::new(width, height, colors::BLACK),
+        }
+    }
+
+    pub fn set(&mut self, x: i32, y: i32, transparent: bool, walkable: bool) {
+        self.sight.set(x, y, transparent, walkable);
+        self.light_fov.set(x, y, transparent, walkable);
+    }
+
+    pub fn compute_fov(&mut self, x: i32, y: i32, radius: i32, light_walls: bool, algo: FovAlgorithm) {
+        self.sight.compute_fov(x, y, radius, light_walls, algo);
+        self.viewer = (x, y);
+    }
+
+    /// Add up the light of all the light sources of the world on every tile.
+    pub fn compute_lighting(&mut self, world: &World) {
+        let mut light = Grid::new(self.width, self.height, (0.0, 0.0, 0.0));
+        for (id, &source) in world.lights.iter() {
+            let (source_x, source_y) = world.pos(id);
+            // Light does not go through walls.
+            self.light_fov.compute_fov(source_x, source_y, source.radius, FOV_LIGHT_WALLS, FOV_ALGO);
+            for y in max(0, source_y - source.radius)..min(self.height, source_y + source.radius + 1) {
+                for x in max(0, source_x - source.radius)..min(self.width, source_x + source.radius + 1) {
+                    let distance = (((x - source_x).pow(2) + (y - source_y).pow(2)) as f32).sqrt();
+                    if distance > source.radius as f32 || !self.light_fov.is_in_fov(x, y) {
+                        continue;
+                    }
+                    let intensity = (1.0 - distance / (source.radius as f32 + 1.0)).powf(source.falloff);
+                    let tile = &mut light[(x, y)];
+                    tile.0 += source.color.r as f32 * intensity;
+                    tile.1 += source.color.g as f32 * intensity;
+                    tile.2 += source.color.b as f32 * intensity;
+                }
+            }
+        }
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (r, g, b) = light[(x, y)];
+                self.light[(x, y)] = Color { r: r.min(255.0) as u8, g: g.min(255.0) as u8, b: b.min(255.0) as u8 };
+            }
+        }
+    }
+
+    pub fn light(&self, x: i32, y: i32) -> Color {
+        self.light[(x, y)]
+    }
+
+    pub fn is_lit(&self, x: i32, y: i32) -> bool {
+        let Color { r, g, b } = self.light(x, y);
+        max(r, max(g, b)) as f32 >= MIN_VISIBLE_LIGHT * 255.0
+    }
+
+    pub fn is_in_fov(&self, x: i32, y: i32) -> bool {
+        (x, y) == self.viewer || (self.sight.is_in_fov(x, y) && self.is_lit(x, y))
+    }
+}
+
+/// Colour of a visible tile: each channel goes from its dark colour to its lit colour depending
+/// on how much light of that channel the tile receives.
+fn lit_color(dark: Color, lit: Color, light: Color) -> Color {
+    let blend = |dark: u8, lit: u8, light: u8| {
+        (dark as f32 + (lit as f32 - dark as f32) * light as f32 / 255.0) as u8
+    };
+    Color {
+        r: blend(dark.r, lit.r, light.r),
+        g: blend(dark.g, lit.g, light.g),
+        b: blend(dark.b, lit.b, light.b),
+    }
+}
+
+/// Copy whether each tile of the map can be seen through and crossed into the FOV map.
+fn update_fov_map(map: &Map, fov_map: &mut FovMap) {
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            let tile = &map[(x, y)];
+            fov_map.set(x, y, tile.transparent, tile.traversable);
+        }
+    }
+}
+
+fn render_all(screen: &mut Screen,
+              world: &World,
+              messages: &Messages,
+              map: &mut Map,
+              fov_map: &mut FovMap,
+              fov_recompute: bool) {
+    let Screen { root, con, panel, minimap, camera, show_minimap, mouse } = screen;
+    if fov_recompute {
+        // Recompute FOV if needed (the player moved or something).
+        let (player_x, player_y) = world.pos(PLAYER_ID);
+        fov_map.compute_fov(player_x, player_y, world.sight_radius(PLAYER_ID), FOV_LIGHT_WALLS, FOV_ALGO);
+        fov_map.compute_lighting(world);
+
+        // Since they are visible, we should mark them as explored, even if they are off screen,
+        // and remember which ones have items.
+        for y in 0..MAP_HEIGHT {
+            for x in 0..MAP_WIDTH {
+                if fov_map.is_in_fov(x, y) {
+                    let tile = &mut map[(x, y)];
+                    tile.explored = true;
+                    tile.seen_item = None;
+                }
+            }
+        }
+        for (id, _) in world.items.iter() {
+            let (x, y) = world.pos(id);
+            if fov_map.is_in_fov(x, y) {
+                map[(x, y)].seen_item = Some(world.renderables[id]);
+            }
+        }
+    }
+
+    for screen_y in 0..CAMERA_HEIGHT {
+        for screen_x in 0..CAMERA_WIDTH {
+            let (x, y) = match camera.to_map(screen_x, screen_y) {
+                Some(position) => position,
+                None => continue,
+            };
+            let visible = fov_map.is_in_fov(x, y);
+            let tile = map[(x, y)];
+            let wall = tile.is_wall();
+
+            let (dark, light) = if wall {
+                (COLOR_DARK_WALL, COLOR_LIGHT_WALL)
+            } else {
+                (COLOR_DARK_GROUND, COLOR_LIGHT_GROUND)
+            };
+            let color = if visible && world.events.ui.flashes.contains(&(x, y)) {
+                COLOR_HIT_FLASH
+            } else if visible {
+                lit_color(dark, light, fov_map.light(x, y))
+            } else {
+                dark
+            };
+            if tile.explored {
+                con.set_char_background(screen_x, screen_y, color, BackgroundFlag::Set);
+                if let Some(glyph) = tile.glyph() {
+                    con.set_default_foreground(if visible { COLOR_LIGHT_DOOR } else { COLOR_DARK_DOOR });
+                    con.put_char(screen_x, screen_y, glyph, BackgroundFlag::None);
+                }
+                if let Some(trap) = tile.trap.filter(|trap| !trap.hidden) {
+                    con.set_default_foreground(if visible { trap.kind.color() } else { COLOR_DARK_WALL });
+                    con.put_char(screen_x, screen_y, '^', BackgroundFlag::None);
+                }
+            }
+        }
+    }
+
+    // Draw all the visible entities.
+    let mut to_draw: Vec<_> = world.renderables.iter().filter(|&(id, renderable)| {
+        let (x, y) = world.pos(id);
+        fov_map.is_in_fov(x, y) || (renderable.always_visible && map[(x, y)].explored)
+    }).collect();
+    // Sort to put non-blocking entities first.
+    to_draw.sort_by_key(|&(id, _)| world.blocks.contains(id));
+    for (id, renderable) in to_draw {
+        renderable.draw(con, world.positions[id], camera);
+    }
+
+    // Overlay the console over the root.
+    blit(con, (0, 0), (CAMERA_WIDTH, CAMERA_HEIGHT), root, (0, 0), 1.0, 1.0);
+
+    if *show_minimap {
+        render_minimap(minimap, map, world);
+        blit(minimap, (0, 0), (MINIMAP_WIDTH, MINIMAP_HEIGHT), root,
+             (SCREEN_WIDTH - MINIMAP_WIDTH, 0), 1.0, 0.8);
+    }
+
+
+    // Show the player stats
+    if let Some(fighter) = world.fighters.get(PLAYER_ID) {
+        // Prepare to renter the GUI panel.
+        panel.set_default_background(colors::BLACK);
+        panel.clear();
+
+        render_bar(
+            panel,
+            1,
+            1,
+            BAR_WIDTH,
+            "HP",
+            fighter.hp,
+            fighter.max_hp,
+            colors::LIGHT_RED,
+            colors::DARKER_RED,
+            );
+        render_effects(panel, 1, 2, &world.effects[PLAYER_ID]);
+
+        panel.set_default_foreground(colors::LIGHT_GREY);
+        panel.print_ex(
+            1,
+            4,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            format!("Dungeon level {}", world.depth),
+            );
+
+        // display names of objects under the mouse
+        panel.set_default_foreground(colors::LIGHT_GREY);
+        panel.print_ex(
+            1,
+            0,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            get_names_under_mouse(*mouse, world, fov_map, camera),
+            );
+
+        render_messages(messages, panel);
+
+        blit(
+            panel,
+            (0, 0),
+            (SCREEN_WIDTH, SCREEN_HEIGHT),
+            root,
+            (0, PANEL_Y),
+            1.0,
+            1.0,
+        );
+    }
+}
+
+/// Let the player pick a visible tile within the given range, moving a cursor with the movement
+/// keys. The cursor starts on the closest monster, if any, and the line of fire to it is
+/// highlighted. Enter confirms, Escape cancels.
+fn target_tile(root: &mut Root, world: &World, fov_map: &FovMap, camera: &Camera, max_range: i32)
+    -> Option<(i32, i32)> {
+    let player_pos = world.pos(PLAYER_ID);
+    let mut cursor = closest_monster(max_range, world, fov_map)
+        .map_or(player_pos, |id| world.pos(id));
+
+    root.set_default_background(colors::BLACK);
+    root.rect(0, PANEL_Y, MSG_X, 1, true, BackgroundFlag::Set);
+    root.set_default_foreground(colors::LIGHT_GREY);
+    root.print_ex(1, PANEL_Y, BackgroundFlag::None, TextAlignment::Left, "Target: Enter / Esc");
+
+    loop {
+        // Highlight the line of fire and the cursor, remembering the previous colours.
+        let mut cells = line(player_pos, cursor);
+        if cells.is_empty() {
+            cells.push(cursor);
+        }
+        let cells: Vec<(i32, i32)> = cells.into_iter().filter_map(|(x, y)| camera.to_screen(x, y)).collect();
+        let previous: Vec<Color> = cells.iter().map(|&(x, y)| root.get_char_background(x, y)).collect();
+        for &(x, y) in &cells {
+            root.set_char_background(x, y, COLOR_LINE_OF_FIRE, BackgroundFlag::Set);
+        }
+        if let Some((x, y)) = camera.to_screen(cursor.0, cursor.1) {
+            root.set_char_background(x, y, COLOR_TARGET, BackgroundFlag::Set);
+        }
+        root.flush();
+
+        let key = root.wait_for_keypress(true);
+        for (&(x, y), &color) in cells.iter().zip(previous.iter()) {
+            root.set_char_background(x, y, color, BackgroundFlag::Set);
+        }
+
+        match key {
+            Key { code: Enter, .. } => {
+                let in_range = (((cursor.0 - player_pos.0).pow(2) + (cursor.1 - player_pos.1).pow(2)) as f32)
+                    .sqrt() <= max_range as f32;
+                if cursor != player_pos && in_range && fov_map.is_in_fov(cursor.0, cursor.1) {
+                    return Some(cursor);
+                }
+            }
+            Key { code: Escape, .. } => return None,
+            _ => {
+                if let Some((dx, dy)) = key_direction(key) {
+                    // The cursor stays on screen.
+                    let next = (cursor.0 + dx, cursor.1 + dy);
+                    if camera.to_screen(next.0, next.1).is_some() {
+                        cursor = next;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root) -> Option<usize> {
+    assert!(options.len() <= 26, "Cannot have a menu with more than 26 options.");
+
+    // Calculate the total height for the header (after auto-wrap) and one line per option.
+    let header_height = root.get_height_rect(0, 0, width, SCREEN_HEIGHT, header);
+    let height = options.len() as i32 + header_height;
+
+    // Create an off-screen console that represents the menu's window
+    let mut window = Offscreen::new(width, height);
+
+    // Print the header, with auto-wrap, and all the options, without wrap.
+    window.set_default_foreground(colors::WHITE);
+    window.print_rect_ex(0, 0, width, height, BackgroundFlag::None, TextAlignment::Left, header);
+
+    for (index, option_text) in options.iter().enumerate() {
+        let menu_letter = (b'a' + index as u8) as char;
+        let text = format!("{} - {}", menu_letter, option_text.as_ref());
+        window.print_ex(
+            0,
+            header_height + index as i32,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            text
+        );
+    }
+    // Blit the contents of "window" to the center of the root console.
+    // The last two params of blit are FG and BG transparency.
+    let x = SCREEN_WIDTH / 2 - width / 2;
+    let y = SCREEN_HEIGHT / 2 - height / 2;
+    tcod::console::blit(&mut window, (0, 0), (width, height), root, (x, y), 1.0, 0.7);
+
+    // Present the contents and wait for keypress (so that it doesn't disappear immediately).
+    root.flush();
+    let key = root.wait_for_keypress(true);
+
+    if key.printable.is_alphabetic() {
+        let index = key.printable.to_ascii_lowercase() as usize - 'a' as usize;
+        if index < options.len() {
+            Some(index)
+        } else {
+            None
+        }
+    } else {
+        None
+    }
+}
+
+/// Show some text in the middle of the screen until a key is pressed.
+fn msgbox(text: &str, width: i32, root: &mut Root) {
+    let options: &[&str] = &[];
+    menu(text, options, width, root);
+}
+
+/// What the player learns from looking at a tile: the fighter standing on it, if any, or the
+/// other things lying there.
+fn describe_tile(x: i32, y: i32, world: &World) -> String {
+    if let Some(id) = world.fighter_at(x, y) {
+        let fighter = &world.fighters[id];
+        return format!("{}\n\nHit points: {}/{}\n{}",
+                       capitalize(&world.names[id]), fighter.hp, fighter.max_hp, describe_affinities(fighter));
+    }
+    let names: Vec<&str> = world.entities_at(x, y).iter().map(|&id| world.names[id].as_str()).collect();
+    if names.is_empty() {
+        "Nothing of interest.".into()
+    } else {
+        capitalize(&names.join(", "))
+    }
+}
+
+fn character_screen(world: &World) -> String {
+    let fighter = &world.fighters[PLAYER_ID];
+    let worn = fighter.worn.map_or("none", ArmourKind::name);
+    format!("Character\n\nHit points: {}/{}\nAccuracy: {}\nEvasion: {}\nDamage: {} {}\nArmour: {}\n\
+             Wearing: {}\n\n{}",
+            fighter.hp, fighter.max_hp, fighter.accuracy, fighter.evasion, fighter.damage,
+            fighter.damage_type.name(), fighter.total_armour(), worn, describe_affinities(fighter))
+}
+
+fn inventory_menu(inventory: &[Object], header: &str, root: &mut Root) -> Option<usize> {
+    // how a menu with each item of the inventory as an option
+    let options = if inventory.len() == 0 {
+        vec!["Inventory is empty.".into()]
+    } else {
+        inventory.iter().map(|item| { item.display_name() }).collect()
+    };
+
+    let inventory_index = menu(header, &options, INVENTORY_WIDTH, root);
+
+    // if an item was chosen, return it
+    if inventory.len() > 0 {
+        inventory_index
+    } else {
+        None
+    }
+}
+
+fn render_messages(messages: &Messages, panel: &mut Offscreen) {
+    let mut y = MSG_HEIGHT as i32;
+    for message in messages.history.iter().rev() {
+        let msg = message.display_text();
+        let msg_height = panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, &msg);
+        y -= msg_height;
+        if y < 0 {
+            break;
+        }
+        panel.set_default_foreground(message.color);
+        panel.print_rect(MSG_X, y, MSG_WIDTH, 0, &msg);
+    }
+}
+
+/// Show the full message log, one message per line, until the player presses Escape.
+///
+/// The log can be scrolled line by line (j/k or arrows), page by page (PageUp/PageDown) and
+/// searched ('/'), in which case only the messages containing the search text are listed.
+fn message_history(messages: &Messages, root: &mut Root) {
+    let page_height = (SCREEN_HEIGHT - 2) as usize;
+    let mut window = Offscreen::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+    let mut search: Option<String> = None;
+    // Number of lines between the last line shown and the end of the log.
+    let mut scroll = 0;
+
+    loop {
+        let lines: Vec<&Message> = messages.history
+            .iter()
+            .filter(|m| search.as_ref().map_or(true, |s| m.text.to_lowercase().contains(s)))
+            .collect();
+        let max_scroll = lines.len().saturating_sub(page_height);
+        scroll = min(scroll, max_scroll);
+        let end = lines.len() - scroll;
+        let start = end.saturating_sub(page_height);
+
+        window.set_default_background(colors::BLACK);
+        window.clear();
+        window.set_default_foreground(colors::WHITE);
+        let header = match search {
+            Some(ref s) => format!("Message history - {} messages matching \"{}\"", lines.len(), s),
+            None => format!("Message history - {} messages", lines.len()),
+        };
+        window.print_ex(0, 0, BackgroundFlag::None, TextAlignment::Left, header);
+        for (index, message) in lines[start..end].iter().enumerate() {
+            window.set_default_foreground(message.color);
+            window.print_ex(
+                0,
+                1 + index as i32,
+                BackgroundFlag::None,
+                TextAlignment::Left,
+                format!("{:>5} {}", message.turn, message.display_text()),
+            );
+        }
+        window.set_default_foreground(colors::LIGHT_GREY);
+        window.print_ex(
+            0,
+            SCREEN_HEIGHT - 1,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            "j/k: scroll, PgUp/PgDn: page, /: search, Esc: back",
+        );
+        blit(&window, (0, 0), (SCREEN_WIDTH, SCREEN_HEIGHT), root, (0, 0), 1.0, 1.0);
+        root.flush();
+
+        match root.wait_for_keypress(true) {
+            Key { code: Up, .. } | Key { code: Char, printable: 'k', .. } => {
+                scroll = min(scroll + 1, max_scroll)
+            }
+            Key { code: Down, .. } | Key { code: Char, printable: 'j', .. } => {
+                scroll = scroll.saturating_sub(1)
+            }
+            Key { code: PageUp, .. } => scroll = min(scroll + page_height, max_scroll),
+            Key { code: PageDown, .. } => scroll = scroll.saturating_sub(page_height),
+            Key { code: Home, .. } => scroll = max_scroll,
+            Key { code: End, .. } => scroll = 0,
+            Key { code: Char, printable: '/', .. } => {
+                search = prompt_text("Search: ", root)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.to_lowercase());
+                scroll = 0;
+            }
+            // Escape clears the search first, then leaves the history.
+            Key { code: Escape, .. } if search.is_some() => search = None,
+            Key { code: Escape, .. } => break,
+            _ => {}
+        }
+    }
+}
+
+/// Ask the player for a direction, showing the prompt on the bottom line of the screen.
+/// Returns None if anything but a movement key was pressed.
+fn prompt_direction(prompt: &str, root: &mut Root) -> Option<(i32, i32)> {
+    root.set_default_background(colors::BLACK);
+    root.rect(0, SCREEN_HEIGHT - 1, SCREEN_WIDTH, 1, true, BackgroundFlag::Set);
+    root.set_default_foreground(colors::WHITE);
+    root.print_ex(0, SCREEN_HEIGHT - 1, BackgroundFlag::None, TextAlignment::Left, prompt);
+    root.flush();
+
+    key_direction(root.wait_for_keypress(true))
+}
+
+/// Read a line of text typed by the player on the bottom line of the screen.
+/// Returns None if the player cancelled with Escape.
+fn prompt_text(prompt: &str, root: &mut Root) -> Option<String> {
+    let mut text = String::new();
+    loop {
+        root.set_default_background(colors::BLACK);
+        root.rect(0, SCREEN_HEIGHT - 1, SCREEN_WIDTH, 1, true, BackgroundFlag::Set);
+        root.set_default_foreground(colors::WHITE);
+        root.print_ex(
+            0,
+            SCREEN_HEIGHT - 1,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            format!("{}{}_", prompt, text),
+        );
+        root.flush();
+
+        let key = root.wait_for_keypress(true);
+        match key.code {
+            Enter => return Some(text),
+            Escape => return None,
+            Backspace => {
+                text.pop();
+            }
+            _ => {
+                if key.printable != '\0' && !key.printable.is_control() {
+                    text.push(key.printable);
+                }
+            }
+        }
+    }
+}
+
+
+/// Map generation algorithms.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MapStyle {
+    /// Rectangular rooms placed at random, each connected to the previous one.
+    Rooms,
+    /// Rooms in a binary space partition of the map, connected to their siblings, with the given
+    /// number of extra connections.
+    Bsp { extra_loops: i32 },
+    /// Natural caves grown by a cellular automaton.
+    Caves,
+}
+
+impl MapStyle {
+    /// Style of the map for the given dungeon level: every third level is a cave, and the other
+    /// levels alternate between random rooms and partitioned rooms.
+    fn for_level(dungeon_level: u32) -> Self {
+        match dungeon_level % 3 {
+            0 => MapStyle::Caves,
+            1 => MapStyle::Rooms,
+            _ => MapStyle::Bsp { extra_loops: BSP_EXTRA_LOOPS },
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "rooms" => Some(MapStyle::Rooms),
+            "bsp" => Some(MapStyle::Bsp { extra_loops: BSP_EXTRA_LOOPS }),
+            "caves" => Some(MapStyle::Caves),
+            _ => None,
+        }
+    }
+
+    /// Generate a map in this style, and place objects in it.
+    /// Returns the map and the player's starting position, or why no map could be generated.
+    fn generate(self, objects: &mut Vec<Object>) -> Result<(Map, (i32, i32)), MapError> {
+        match self {
+            MapStyle::Rooms => Ok(make_rooms_map(objects)),
+            MapStyle::Bsp { extra_loops } => Ok(make_bsp_map(extra_loops, objects)),
+            MapStyle::Caves => make_cave_map(objects),
+        }
+    }
+}
+
+/// Generate the map for the given dungeon level, and spawn its objects in the world.
+/// Returns the map and the player's starting position.
+fn make_map(world: &mut World, dungeon_level: u32) -> (Map, (i32, i32)) {
+    world.depth = dungeon_level;
+    let (map, objects, start) = generate_level(MapStyle::for_level(dungeon_level), dungeon_level);
+    for object in objects {
+        world.spawn(object);
+    }
+    (map, start)
+}
+
+/// Generate a level in the given style for the given dungeon level. Levels which do not pass
+/// `validate_map` are thrown away and generated again.
+/// Returns the map, the objects to spawn in it and the player's starting position.
+///
+/// Panics if no valid level is generated in MAX_MAP_ATTEMPTS attempts, which means a generator
+/// is broken.
+fn generate_level(style: MapStyle, dungeon_level: u32) -> (Map, Vec<Object>, (i32, i32)) {
+    let mut last_error = None;
+    for _ in 0..MAX_MAP_ATTEMPTS {
+        let mut objects = vec![];
+        let level = style.generate(&mut objects).and_then(|(map, start)| {
+            if dungeon_level.is_multiple_of(BOSS_LEVEL_INTERVAL) {
+                place_boss(&map, &mut objects);
+            }
+            validate_map(&map, &objects, start).map(|()| (map, start))
+        });
+        match level {
+            Ok((map, start)) => return (map, objects, start),
+            Err(error) => last_error = Some(error),
+        }
+    }
+    panic!("No valid {:?} level generated in {} attempts, last error: {}",
+           style, MAX_MAP_ATTEMPTS, last_error.expect("No attempt made"));
+}
+
+/// Put a boss on the stairs, in place of any monster already there. Trapped stairs are left
+/// alone, since `validate_map` rejects them anyway.
+fn place_boss(map: &Map, objects: &mut Vec<Object>) {
+    let stairs = match objects.iter().find(|o| o.is_stairs()) {
+        Some(stairs) if map[stairs.pos()].trap.is_none() => stairs.pos(),
+        _ => return,
+    };
+    objects.retain(|o| o.traversable || o.pos() != stairs);
+    objects.push(make_monster(MonsterKind::OrcWarlord, stairs.0, stairs.1));
+}
+
+/// Something wrong with a generated level.
+#[derive(Clone, Debug, PartialEq)]
+enum MapError {
+    StartInWall,
+    UnreachableTile(i32, i32),
+    ObjectInWall { name: String, x: i32, y: i32 },
+    UnreachableObject { name: String, x: i32, y: i32 },
+    MissingStairs,
+    TrappedStairs,
+    CaveTooSmall,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::StartInWall => write!(f, "the player starts in a wall"),
+            MapError::UnreachableTile(x, y) => write!(f, "floor at ({}, {}) cannot be reached", x, y),
+            MapError::ObjectInWall { name, x, y } => write!(f, "{} at ({}, {}) is in a wall", name, x, y),
+            MapError::UnreachableObject { name, x, y } =>
+                write!(f, "{} at ({}, {}) cannot be reached", name, x, y),
+            MapError::MissingStairs => write!(f, "there are no stairs down"),
+            MapError::TrappedStairs => write!(f, "there is a trap under the stairs"),
+            MapError::CaveTooSmall => write!(f, "no cave was big enough in {} attempts", MAX_CAVE_ATTEMPTS),
+        }
+    }
+}
+
+/// Check the invariants every generated level must hold: the player can walk from the start to
+/// every floor tile and every object, nothing is inside a wall, and there are untrapped stairs
+/// down.
+/// Doors count as floor, whether they are locked or secret.
+fn validate_map(map: &Map, objects: &[Object], start: (i32, i32)) -> Result<(), MapError> {
+    if !map[start].is_passable() {
+        return Err(MapError::StartInWall);
+    }
+
+    let distances = walking_distances(map, start);
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            if map[(x, y)].is_passable() && distances[(x, y)].is_none() {
+                return Err(MapError::UnreachableTile(x, y));
+            }
+        }
+    }
+
+    for object in objects {
+        let (x, y) = object.pos();
+        let name = object.name.clone();
+        if !map.get(x, y).map_or(false, |tile| tile.is_passable()) {
+            return Err(MapError::ObjectInWall { name, x, y });
+        }
+        if distances[(x, y)].is_none() {
+            return Err(MapError::UnreachableObject { name, x, y });
+        }
+    }
+
+    match objects.iter().find(|o| o.is_stairs()) {
+        None => Err(MapError::MissingStairs),
+        Some(stairs) if map[stairs.pos()].trap.is_some() => Err(MapError::TrappedStairs),
+        Some(_) => Ok(()),
+    }
+}
+
+fn make_rooms_map(objects: &mut Vec<Object>) -> (Map, (i32, i32)) {
+    // Fill map with untraversable tiles
+    let mut map = Grid::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
+
+    let mut starting_position = (0, 0);
+    let mut rooms: Vec<Rect> = Vec::new();
+    let mut vaults: Vec<Rect> = Vec::new();
+
+    for _ in 0..MAX_ROOMS {
+        // Random width / height
+        let w = rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+        let h = rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+
+        // random position without going out of the boundaries of the map.
+        let x = rng().gen_range(0, MAP_WIDTH - w);
+        let y = rng().gen_range(0, MAP_HEIGHT - h);
+
+        let new_room = Rect::new(x, y, w, h);
+
+        // If this room intersects with another one or with a vault, it is considered invalid.
+        let invalid = rooms.iter().chain(&vaults).any(|other_room| new_room.intersects_with(other_room));
+        if invalid {
+            continue;
+        }
+
+        create_room(new_room, &mut map);
+        let (new_x, new_y) = new_room.center();
+
+        if rooms.is_empty() {
+            // Player starts in first room, no tunnel needed.
+            starting_position = (new_x, new_y);
+        } else {
+            // Place traps, then objets (monsters, items, ...) where there is no trap.
+            place_traps(&new_room, &mut map);
+            place_objects(&new_room, &map, objects);
+
+            // All other rooms should be connected with the previous one.
+            connect_rooms(&rooms[rooms.len() - 1], &new_room, &mut map);
+
+            // Some come with a vault, stamped wherever it fits.
+            if vaults.len() < MAX_VAULTS && rng().gen::<f32>() < VAULT_CHANCE {
+                let whole_map = Rect::new(0, 0, MAP_WIDTH - 1, MAP_HEIGHT - 1);
+                if let Some((area, entrances)) = place_vault(&whole_map, &mut map, objects) {
+                    connect_vault(&area, &entrances, &mut map);
+                    vaults.push(area);
                 }
             }
         }
+
+        rooms.push(new_room);
+
+    }
+
+    place_doors(&rooms, &mut map, objects);
+
+    // The stairs down are in the last room, unless a vault has some.
+    if !objects.iter().any(|o| o.is_stairs()) {
+        let (stairs_x, stairs_y) = rooms[rooms.len() - 1].center();
+        place_stairs(stairs_x, stairs_y, &mut map, objects);
+    }
+
+    (map, starting_position)
+
+}
+
+/// Generate a level by recursively splitting the map in two, putting a room or a vault in each
+/// final part, and connecting the rooms of each pair of sibling parts. Extra connections are then added
+/// between neighbouring rooms so that the dungeon is not a single chain.
+/// Returns the map and the player's starting position.
+fn make_bsp_map(extra_loops: i32, objects: &mut Vec<Object>) -> (Map, (i32, i32)) {
+    let mut map = Grid::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
+    let mut rooms = vec![];
+    let mut connections = vec![];
+    let mut vaults = vec![];
+    split_bsp(Rect::new(0, 0, MAP_WIDTH - 1, MAP_HEIGHT - 1), &mut map, &mut rooms, &mut connections,
+              &mut vaults, objects);
+
+    for _ in 0..extra_loops {
+        // Connect a random room to the closest room it is not connected to yet.
+        let from = rng().gen_range(0, rooms.len());
+        let closest = (0..rooms.len())
+            .filter(|&to| to != from && !connections.contains(&(min(from, to), max(from, to))))
+            .min_by_key(|&to| room_distance(&rooms[from], &rooms[to]));
+        if let Some(to) = closest {
+            connect_rooms(&rooms[from], &rooms[to], &mut map);
+            connections.push((min(from, to), max(from, to)));
+        }
+    }
+
+    // The player starts in the first room, and the stairs are in the last one, which is in the
+    // opposite part of the partition.
+    for room in &rooms[1..] {
+        place_traps(room, &mut map);
+        place_objects(room, &map, objects);
+    }
+    // Vaults are only connected once all the rooms are, so that they join the closest one.
+    for (area, entrances) in &vaults {
+        connect_vault(area, entrances, &mut map);
+    }
+    place_doors(&rooms, &mut map, objects);
+    if !objects.iter().any(|o| o.is_stairs()) {
+        let (stairs_x, stairs_y) = rooms[rooms.len() - 1].center();
+        place_stairs(stairs_x, stairs_y, &mut map, objects);
+    }
+
+    (map, rooms[0].center())
+}
+
+/// Split an area of the map in two until its parts are too small, and put a room or a vault in
+/// each of them. Rooms of sibling parts are connected by their closest pair, which is recorded in
+/// `connections` as a pair of room indices. Vaults are recorded in `vaults` with their entrances,
+/// and left for the caller to connect.
+fn split_bsp(area: Rect, map: &mut Map, rooms: &mut Vec<Rect>, connections: &mut Vec<(usize, usize)>,
+             vaults: &mut Vec<(Rect, Vec<(i32, i32)>)>, objects: &mut Vec<Object>) {
+    let w = area.x2 - area.x1;
+    let h = area.y2 - area.y1;
+    let can_split_x = w >= 2 * BSP_MIN_LEAF_SIZE;
+    let can_split_y = h >= 2 * BSP_MIN_LEAF_SIZE;
+
+    if !can_split_x && !can_split_y {
+        // Leaf: put a vault in it, if it fits, or a room of random size anywhere in it.
+        if vaults.len() < MAX_VAULTS && rng().gen::<f32>() < VAULT_CHANCE {
+            if let Some(vault) = place_vault(&area, map, objects) {
+                vaults.push(vault);
+                return;
+            }
+        }
+        let room_w = rng().gen_range(BSP_MIN_ROOM_SIZE, w + 1);
+        let room_h = rng().gen_range(BSP_MIN_ROOM_SIZE, h + 1);
+        let x = rng().gen_range(area.x1, area.x2 - room_w + 1);
+        let y = rng().gen_range(area.y1, area.y2 - room_h + 1);
+        let room = Rect::new(x, y, room_w, room_h);
+        create_room(room, map);
+        rooms.push(room);
+        return;
+    }
+
+    // Split across the longest side, so that parts stay roughly square.
+    let split_x = if can_split_x && can_split_y { w * MAP_HEIGHT >= h * MAP_WIDTH } else { can_split_x };
+    let (first, second) = if split_x {
+        let split = rng().gen_range(BSP_MIN_LEAF_SIZE, w - BSP_MIN_LEAF_SIZE + 1);
+        (Rect::new(area.x1, area.y1, split, h), Rect::new(area.x1 + split, area.y1, w - split, h))
+    } else {
+        let split = rng().gen_range(BSP_MIN_LEAF_SIZE, h - BSP_MIN_LEAF_SIZE + 1);
+        (Rect::new(area.x1, area.y1, w, split), Rect::new(area.x1, area.y1 + split, w, h - split))
+    };
+
+    let start = rooms.len();
+    split_bsp(first, map, rooms, connections, vaults, objects);
+    let middle = rooms.len();
+    split_bsp(second, map, rooms, connections, vaults, objects);
+    if start == middle || middle == rooms.len() {
+        // One of the parts only holds vaults.
+        return;
+    }
+
+    let mut closest = (start, middle);
+    for i in start..middle {
+        for j in middle..rooms.len() {
+            if room_distance(&rooms[i], &rooms[j]) < room_distance(&rooms[closest.0], &rooms[closest.1]) {
+                closest = (i, j);
+            }
+        }
     }
+    connect_rooms(&rooms[closest.0], &rooms[closest.1], map);
+    connections.push(closest);
+}
 
+/// Squared distance between the centers of two rooms.
+fn room_distance(room: &Rect, other: &Rect) -> i32 {
+    let (x, y) = room.center();
+    let (other_x, other_y) = other.center();
+    (x - other_x).pow(2) + (y - other_y).pow(2)
 }
 
-fn render_all(root: &mut Root,
-              con: &mut Offscreen,
-              panel: &mut Offscreen,
-              mouse: Mouse,
-              objects: &[Object],
-              messages: &Messages,
-              map: &mut Map,
-              fov_map: &mut FovMap,
-              fov_recompute: bool) {
-    if fov_recompute {
-        // Recompute FOV if needed (the player moved or something).
-        let player = &objects[PLAYER_ID];
-        fov_map.compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+/// Dig an L-shaped tunnel between the centers of two rooms.
+fn connect_rooms(from: &Rect, to: &Rect, map: &mut Map) {
+    let (prev_x, prev_y) = from.center();
+    let (new_x, new_y) = to.center();
+
+    // We randomly use tunnel_x or tunnel_y first.
+    if rng().gen() {
+        create_h_tunnel(prev_x, new_x, prev_y, map);
+        create_v_tunnel(prev_y, new_y, new_x, map);
+    } else {
+        // first move vertically, then horizontally
+        create_v_tunnel(prev_y, new_y, prev_x, map);
+        create_h_tunnel(prev_x, new_x, new_y, map);
+    }
+}
+
+/// Generate a cave level, and place objects in it.
+/// Returns the map and the player's starting position, or an error if the caves grown in
+/// MAX_CAVE_ATTEMPTS attempts were all too cramped.
+fn make_cave_map(objects: &mut Vec<Object>) -> Result<(Map, (i32, i32)), MapError> {
+    for _ in 0..MAX_CAVE_ATTEMPTS {
+        let mut map = random_cave();
+        let cave = keep_largest_cave(&mut map);
+        if (cave.len() as f32) < CAVE_MIN_FLOOR_RATIO * (MAP_WIDTH * MAP_HEIGHT) as f32 {
+            // Too cramped, try again.
+            continue;
+        }
+
+        // Start anywhere, and put the stairs as far as possible from there.
+        let start = cave[rng().gen_range(0, cave.len())];
+        let distances = walking_distances(&map, start);
+        let distance = |&(x, y): &(i32, i32)| distances[(x, y)].unwrap_or(0);
+        let stairs = *cave.iter().max_by_key(|position| distance(position)).unwrap();
+
+        place_cave_objects(&cave, &distances, &mut map, objects);
+        place_stairs(stairs.0, stairs.1, &mut map, objects);
+
+        return Ok((map, start));
+    }
+    Err(MapError::CaveTooSmall)
+}
+
+/// Grow caves with a cellular automaton: start from random noise, then repeatedly turn each
+/// tile into a wall if most of its neighbours are walls, and into floor otherwise.
+fn random_cave() -> Map {
+    let mut map = Grid::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
+    for y in 1..(MAP_HEIGHT - 1) {
+        for x in 1..(MAP_WIDTH - 1) {
+            if rng().gen::<f32>() >= CAVE_WALL_CHANCE {
+                map[(x, y)] = Tile::empty();
+            }
+        }
+    }
+
+    for _ in 0..CAVE_SMOOTHING_STEPS {
+        let previous = map.clone();
+        for y in 1..(MAP_HEIGHT - 1) {
+            for x in 1..(MAP_WIDTH - 1) {
+                let walls = previous.neighbours(x, y).filter(|&position| !previous[position].traversable).count();
+                let was_wall = !previous[(x, y)].traversable;
+                map[(x, y)] = if walls >= 5 || (walls == 4 && was_wall) {
+                    Tile::wall()
+                } else {
+                    Tile::empty()
+                };
+            }
+        }
     }
+    map
+}
+
+/// Fill all the caves but the largest one, so that every floor tile is reachable.
+/// Returns the floor tiles of the remaining cave.
+fn keep_largest_cave(map: &mut Map) -> Vec<(i32, i32)> {
+    let mut seen = Grid::new(MAP_WIDTH, MAP_HEIGHT, false);
+    let mut largest: Vec<(i32, i32)> = vec![];
 
     for y in 0..MAP_HEIGHT {
         for x in 0..MAP_WIDTH {
-            let visible = fov_map.is_in_fov(x, y);
-            let wall = !map[x as usize][y as usize].transparent;
-            let explored = &mut map[x as usize][y as usize].explored;
-
-            let color = match(visible, wall) {
-                (false, true) => COLOR_DARK_WALL,
-                (false, false) => COLOR_DARK_GROUND,
-                (true, true) => COLOR_LIGHT_WALL,
-                (true, false) => COLOR_LIGHT_GROUND,
-            };
-            if visible {
-                // Since it's visible, we should mark it as explored.
-                *explored = true;
+            if !map[(x, y)].traversable || seen[(x, y)] {
+                continue;
             }
-            if *explored {
-                con.set_char_background(x, y, color, BackgroundFlag::Set);
+            let distances = walking_distances(map, (x, y));
+            let mut cave = vec![];
+            for cave_x in 0..MAP_WIDTH {
+                for cave_y in 0..MAP_HEIGHT {
+                    if distances[(cave_x, cave_y)].is_some() {
+                        seen[(cave_x, cave_y)] = true;
+                        cave.push((cave_x, cave_y));
+                    }
+                }
+            }
+            if cave.len() > largest.len() {
+                largest = cave;
             }
         }
     }
 
-    // Draw all the objects in the list.
-    let mut to_draw: Vec<_> = objects.iter().filter(|o| fov_map.is_in_fov(o.x, o.y)).collect();
-    // Sort to put non-blocking objects first.
-    to_draw.sort_by(|o1, o2| o2.traversable.cmp(&o1.traversable));
-    for obj in to_draw {
-        if fov_map.is_in_fov(obj.x, obj.y) {
-            obj.draw(con);
+    let mut in_largest = Grid::new(MAP_WIDTH, MAP_HEIGHT, false);
+    for &(x, y) in &largest {
+        in_largest[(x, y)] = true;
+    }
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            if !in_largest[(x, y)] {
+                map[(x, y)] = Tile::wall();
+            }
         }
     }
+    largest
+}
 
-    // Overlay the console over the root.
-    blit(con, (0, 0), (MAP_WIDTH, MAP_HEIGHT), root, (0, 0), 1.0, 1.0);
+/// Number of steps needed to walk from the start to each tile of the map, moving in all eight
+/// directions over passable tiles. Tiles that cannot be reached are None.
+fn walking_distances(map: &Map, start: (i32, i32)) -> Grid<Option<i32>> {
+    let mut distances = Grid::new(MAP_WIDTH, MAP_HEIGHT, None);
+    let mut queue = VecDeque::new();
+    distances[start] = Some(0);
+    queue.push_back(start);
 
+    while let Some((x, y)) = queue.pop_front() {
+        let distance = distances[(x, y)].unwrap_or(0);
+        for next in map.neighbours(x, y) {
+            if distances[next].is_none() && map[next].is_passable() {
+                distances[next] = Some(distance + 1);
+                queue.push_back(next);
+            }
+        }
+    }
+    distances
+}
 
-    // Show the player stats
-    if let Some(fighter) = objects[PLAYER_ID].fighter {
-        // Prepare to renter the GUI panel.
-        panel.set_default_background(colors::BLACK);
-        panel.clear();
+/// Scatter traps, monsters and items over a cave, in proportion to its size. Monsters are placed
+/// in open areas, away from the player's starting position.
+fn place_cave_objects(cave: &[(i32, i32)], distances: &Grid<Option<i32>>, map: &mut Map,
+                      objects: &mut Vec<Object>) {
+    for _ in 0..(cave.len() / CAVE_TILES_PER_TRAP) {
+        let (x, y) = cave[rng().gen_range(0, cave.len())];
+        let tile = &mut map[(x, y)];
+        if tile.trap.is_none() && distances[(x, y)] != Some(0) {
+            tile.trap = Some(Trap { kind: random_trap_kind(), hidden: true });
+        }
+    }
 
-        let hp = objects[PLAYER_ID].fighter.map_or(0, |f| f.hp);
-        let max_hp = objects[PLAYER_ID].fighter.map_or(0, |f| f.max_hp);
-        render_bar(
-            panel,
-            1,
-            1,
-            BAR_WIDTH,
-            "HP",
-            hp,
-            max_hp,
-            colors::LIGHT_RED,
-            colors::DARKER_RED,
-            );
+    let is_open = |x: i32, y: i32| {
+        map[(x, y)].traversable && map.neighbours(x, y).all(|position| map[position].traversable)
+    };
+    let open_areas: Vec<(i32, i32)> = cave.iter().cloned().filter(|&(x, y)| {
+        is_open(x, y) && distances[(x, y)].map_or(false, |d| d >= CAVE_SAFE_DISTANCE)
+    }).collect();
 
-        // display names of objects under the mouse
-        panel.set_default_foreground(colors::LIGHT_GREY);
-        panel.print_ex(
-            1,
-            0,
-            BackgroundFlag::None,
-            TextAlignment::Left,
-            get_names_under_mouse(mouse, objects, fov_map),
-            );
+    if !open_areas.is_empty() {
+        for _ in 0..(cave.len() / CAVE_TILES_PER_MONSTER) {
+            let (x, y) = open_areas[rng().gen_range(0, open_areas.len())];
+            spawn(random_monster(x, y), map, objects);
+        }
+    }
 
-        render_messages(messages, panel);
+    for _ in 0..(cave.len() / CAVE_TILES_PER_ITEM) {
+        let (x, y) = cave[rng().gen_range(0, cave.len())];
+        spawn(random_item(x, y), map, objects);
+    }
 
-        blit(
-            panel,
-            (0, 0),
-            (SCREEN_WIDTH, SCREEN_HEIGHT),
-            root,
-            (0, PANEL_Y),
-            1.0,
-            1.0,
-        );
+    // Caves are dark, apart from some glowing fungi.
+    for _ in 0..(cave.len() / CAVE_TILES_PER_FUNGUS) {
+        let (x, y) = cave[rng().gen_range(0, cave.len())];
+        spawn(make_fungus(x, y), map, objects);
     }
 }
 
-fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root) -> Option<usize> {
-    assert!(options.len() <= 26, "Cannot have a menu with more than 26 options.");
+/// Put the stairs down at the given position, disarming any trap there.
+fn place_stairs(x: i32, y: i32, map: &mut Map, objects: &mut Vec<Object>) {
+    map[(x, y)].trap = None;
+    objects.push(make_stairs(x, y));
+}
 
-    // Calculate the total height for the header (after auto-wrap) and one line per option.
-    let header_height = root.get_height_rect(0, 0, width, SCREEN_HEIGHT, header);
-    let height = options.len() as i32 + header_height;
+fn make_stairs(x: i32, y: i32) -> Object {
+    let mut stairs = Object::new(x, y, '>', "stairs", colors::WHITE, true);
+    stairs.always_visible = true;
+    stairs
+}
 
-    // Create an off-screen console that represents the menu's window
-    let mut window = Offscreen::new(width, height);
+fn create_room(rect: Rect, map: &mut Map) {
+    for y in (rect.y1 + 1)..rect.y2 {
+        for x in (rect.x1 + 1)..rect.x2 {
+            map[(x, y)] = Tile::empty();
+        }
+    }
+}
 
-    // Print the header, with auto-wrap, and all the options, without wrap.
-    window.set_default_foreground(colors::WHITE);
-    window.print_rect_ex(0, 0, width, height, BackgroundFlag::None, TextAlignment::Left, header);
+fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
+    for x in min(x1, x2)..(max(x1, x2) + 1) {
+        map[(x, y)] = Tile::empty();
+    }
+}
 
-    for (index, option_text) in options.iter().enumerate() {
-        let menu_letter = (b'a' + index as u8) as char;
-        let text = format!("{} - {}", menu_letter, option_text.as_ref());
-        window.print_ex(
-            0,
-            header_height + index as i32,
-            BackgroundFlag::None,
-            TextAlignment::Left,
-            text
-        );
+fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
+    for y in min(y1, y2)..(max(y1, y2) + 1) {
+        map[(x, y)] = Tile::empty();
     }
-    // Blit the contents of "window" to the center of the root console.
-    // The last two params of blit are FG and BG transparency.
-    let x = SCREEN_WIDTH / 2 - width / 2;
-    let y = SCREEN_HEIGHT / 2 - height / 2;
-    tcod::console::blit(&mut window, (0, 0), (width, height), root, (x, y), 1.0, 0.7);
+}
 
-    // Present the contents and wait for keypress (so that it doesn't disappear immediately).
-    root.flush();
-    let key = root.wait_for_keypress(true);
+/// Put doors where tunnels go through the walls of the rooms. Most of them are closed, but some
+/// are open, locked or secret. A key is left in the first room for every locked door.
+fn place_doors(rooms: &[Rect], map: &mut Map, objects: &mut Vec<Object>) {
+    for room in rooms {
+        let Rect { x1, y1, x2, y2 } = *room;
+        let mut walls = vec![];
+        for x in (x1 + 1)..x2 {
+            walls.push((x, y1));
+            walls.push((x, y2));
+        }
+        for y in (y1 + 1)..y2 {
+            walls.push((x1, y));
+            walls.push((x2, y));
+        }
 
-    if key.printable.is_alphabetic() {
-        let index = key.printable.to_ascii_lowercase() as usize - 'a' as usize;
-        if index < options.len() {
-            Some(index)
-        } else {
-            None
+        for (x, y) in walls {
+            if x <= 0 || y <= 0 || x >= MAP_WIDTH - 1 || y >= MAP_HEIGHT - 1 {
+                continue;
+            }
+            if !map[(x, y)].traversable || !is_doorway(x, y, map) {
+                continue;
+            }
+
+            // 60% closed, 20% open, 10% locked, 10% secret
+            let roll = rng().gen::<f32>();
+            let state = if roll < 0.6 {
+                DoorState::Closed
+            } else if roll < 0.8 {
+                DoorState::Open
+            } else if roll < 0.9 {
+                DoorState::Locked
+            } else {
+                DoorState::Secret
+            };
+            map[(x, y)] = Tile::door(state);
+
+            if state == DoorState::Locked {
+                let Rect { x1, y1, x2, y2 } = rooms[0];
+                let key_x = rng().gen_range(x1 + 1, x2);
+                let key_y = rng().gen_range(y1 + 1, y2);
+                objects.push(make_item(Item::Key, key_x, key_y));
+            }
+        }
+    }
+}
+
+/// Whether a door fits on the given tile: a passage with walls on two opposite sides, and floor
+/// on the two other ones.
+fn is_doorway(x: i32, y: i32, map: &Map) -> bool {
+    let floor = |x: i32, y: i32| map[(x, y)].traversable;
+    (floor(x - 1, y) && floor(x + 1, y) && !floor(x, y - 1) && !floor(x, y + 1)) ||
+        (floor(x, y - 1) && floor(x, y + 1) && !floor(x - 1, y) && !floor(x + 1, y))
+}
+
+/// Hide traps in a given room.
+fn place_traps(room: &Rect, map: &mut Map) {
+    let num_traps = rng().gen_range(0, MAX_ROOM_TRAPS + 1);
+    let Rect { x1, y1, x2, y2 } = *room;
+
+    for _ in 0..num_traps {
+        let x = rng().gen_range(x1 + 1, x2);
+        let y = rng().gen_range(y1 + 1, y2);
+
+        let tile = &mut map[(x, y)];
+        if tile.traversable && tile.trap.is_none() {
+            tile.trap = Some(Trap { kind: random_trap_kind(), hidden: true });
+        }
+    }
+}
+
+fn random_trap_kind() -> TrapKind {
+    // 25% dart, 20% poison gas, 15% alarm, 10% flash, 15% teleport, 15% pit
+    let roll = rng().gen::<f32>();
+    if roll < 0.25 {
+        TrapKind::Dart
+    } else if roll < 0.45 {
+        TrapKind::PoisonGas
+    } else if roll < 0.6 {
+        TrapKind::Alarm
+    } else if roll < 0.7 {
+        TrapKind::Flash
+    } else if roll < 0.85 {
+        TrapKind::Teleport
+    } else {
+        TrapKind::Pit
+    }
+}
+
+/// Create objects (monsters, items) in a given room.
+fn place_objects(room: &Rect, map: &Map, objects: &mut Vec<Object>) {
+    let num_monsters = rng().gen_range(0, MAX_ROOM_MONSTERS + 1);
+    let Rect { x1, y1, x2, y2 } = *room;
+
+    for _ in 0..num_monsters {
+        let x = rng().gen_range(x1 + 1, x2);
+        let y = rng().gen_range(y1 + 1, y2);
+        spawn(random_monster(x, y), map, objects);
+    }
+    let num_items = rng().gen_range(0, MAX_ROOM_ITEMS + 1);
+    for _ in 0..num_items {
+        let x = rng().gen_range(x1 + 1, x2);
+        let y = rng().gen_range(y1 + 1, y2);
+        spawn(random_item(x, y), map, objects);
+    }
+
+    // Some rooms are lit by torches along their walls, others are dark.
+    if rng().gen::<f32>() < LIT_ROOM_CHANCE {
+        for _ in 0..TORCHES_PER_LIT_ROOM {
+            let (x, y) = match rng().gen_range(0, 4) {
+                0 => (rng().gen_range(x1 + 1, x2), y1 + 1),
+                1 => (rng().gen_range(x1 + 1, x2), y2 - 1),
+                2 => (x1 + 1, rng().gen_range(y1 + 1, y2)),
+                _ => (x2 - 1, rng().gen_range(y1 + 1, y2)),
+            };
+            spawn(make_torch(x, y), map, objects);
+        }
+    }
+    if rng().gen::<f32>() < FIRE_CHANCE {
+        let x = rng().gen_range(x1 + 1, x2);
+        let y = rng().gen_range(y1 + 1, y2);
+        spawn(make_fire(x, y), map, objects);
+    }
+}
+
+fn make_torch(x: i32, y: i32) -> Object {
+    let mut torch = Object::new(x, y, '|', "torch", colors::ORANGE, true);
+    torch.light = Some(Light { radius: 8, color: COLOR_TORCH_LIGHT, falloff: 1.0 });
+    torch
+}
+
+fn make_fire(x: i32, y: i32) -> Object {
+    let mut fire = Object::new(x, y, '~', "fire", colors::FLAME, true);
+    fire.light = Some(Light { radius: 6, color: COLOR_FIRE_LIGHT, falloff: 0.7 });
+    fire
+}
+
+fn make_fungus(x: i32, y: i32) -> Object {
+    let mut fungus = Object::new(x, y, '"', "glowing fungus", colors::LIGHT_GREEN, true);
+    fungus.light = Some(Light { radius: 3, color: COLOR_FUNGUS_LIGHT, falloff: 2.0 });
+    fungus
+}
+
+/// Add an object to the map, unless its tile is already taken or trapped. Monsters would
+/// otherwise spring the trap, or fall through a pit, before the player ever sees them.
+fn spawn(object: Object, map: &Map, objects: &mut Vec<Object>) {
+    let blocked = objects.iter().any(|o| !o.traversable && o.pos() == object.pos());
+    let tile = &map[(object.x, object.y)];
+    if tile.traversable && tile.trap.is_none() && !blocked {
+        objects.push(object);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MonsterKind {
+    Orc,
+    Bat,
+    Zombie,
+    Troll,
+    FireImp,
+    IceGolem,
+    /// Boss guarding the stairs every BOSS_LEVEL_INTERVAL levels.
+    OrcWarlord,
+}
+
+impl MonsterKind {
+    pub fn loot(self) -> Option<LootTable> {
+        match self {
+            MonsterKind::Orc => Some(LootTable {
+                drop_chance: 0.2,
+                entries: &[
+                    LootEntry { item: Item::Heal, weight: 40, min_level: 1 },
+                    LootEntry { item: Item::Ammo(AmmoKind::Arrow), weight: 25, min_level: 1 },
+                    LootEntry { item: Item::Confuse, weight: 10, min_level: 1 },
+                    LootEntry { item: SHORT_BOW, weight: 5, min_level: 1 },
+                    LootEntry { item: Item::Armour(ArmourKind::Leather), weight: 5, min_level: 2 },
+                ],
+                gold_chance: 0.5,
+                gold: Dice { count: 1, sides: 6, bonus: 0 },
+                guaranteed: &[],
+            }),
+            MonsterKind::Bat => None,
+            MonsterKind::Zombie => Some(LootTable {
+                drop_chance: 0.15,
+                entries: &[
+                    LootEntry { item: Item::Heal, weight: 20, min_level: 1 },
+                    LootEntry { item: Item::Regenerate, weight: 10, min_level: 1 },
+                ],
+                gold_chance: 0.3,
+                gold: Dice { count: 1, sides: 4, bonus: 0 },
+                guaranteed: &[],
+            }),
+            MonsterKind::Troll => Some(LootTable {
+                drop_chance: 0.3,
+                entries: &[
+                    LootEntry { item: Item::Heal, weight: 30, min_level: 1 },
+                    LootEntry { item: Item::Haste, weight: 10, min_level: 1 },
+                    LootEntry { item: Item::Ammo(AmmoKind::Bolt), weight: 10, min_level: 1 },
+                    LootEntry { item: Item::Armour(ArmourKind::Leather), weight: 5, min_level: 1 },
+                    LootEntry { item: Item::Fireball, weight: 10, min_level: 3 },
+                    LootEntry { item: CROSSBOW, weight: 5, min_level: 4 },
+                ],
+                gold_chance: 0.6,
+                gold: Dice { count: 2, sides: 6, bonus: 0 },
+                guaranteed: &[],
+            }),
+            MonsterKind::FireImp => Some(LootTable {
+                drop_chance: 0.3,
+                entries: &[
+                    LootEntry { item: Item::Fireball, weight: 20, min_level: 1 },
+                    LootEntry { item: Item::Armour(ArmourKind::Salamander), weight: 5, min_level: 4 },
+                ],
+                gold_chance: 0.3,
+                gold: Dice { count: 1, sides: 8, bonus: 0 },
+                guaranteed: &[],
+            }),
+            MonsterKind::IceGolem => Some(LootTable {
+                drop_chance: 0.4,
+                entries: &[
+                    LootEntry { item: Item::Lightning, weight: 15, min_level: 1 },
+                    LootEntry { item: Item::Heal, weight: 15, min_level: 1 },
+                    LootEntry { item: Item::Haste, weight: 10, min_level: 1 },
+                ],
+                gold_chance: 0.5,
+                gold: Dice { count: 2, sides: 8, bonus: 0 },
+                guaranteed: &[],
+            }),
+            MonsterKind::OrcWarlord => Some(LootTable {
+                drop_chance: 1.0,
+                entries: &[
+                    LootEntry { item: Item::Heal, weight: 20, min_level: 1 },
+                    LootEntry { item: Item::Haste, weight: 20, min_level: 1 },
+                    LootEntry { item: Item::Fireball, weight: 20, min_level: 1 },
+                    LootEntry { item: Item::Lightning, weight: 20, min_level: 1 },
+                    LootEntry { item: CROSSBOW, weight: 10, min_level: 1 },
+                ],
+                gold_chance: 1.0,
+                gold: Dice { count: 3, sides: 10, bonus: 0 },
+                guaranteed: &[Item::Armour(ArmourKind::Salamander)],
+            }),
+        }
+    }
+}
+
+/// Create a random monster at the given position.
+fn random_monster(x: i32, y: i32) -> Object {
+    // 50% chance orc, 15% bat, 10% zombie, 12% troll, 7% fire imp, 6% ice golem
+    let roll = rng().gen::<f32>();
+    let kind = if roll < 0.5 {
+        MonsterKind::Orc
+    } else if roll < 0.65 {
+        MonsterKind::Bat
+    } else if roll < 0.75 {
+        MonsterKind::Zombie
+    } else if roll < 0.87 {
+        MonsterKind::Troll
+    } else if roll < 0.94 {
+        MonsterKind::FireImp
+    } else {
+        MonsterKind::IceGolem
+    };
+    make_monster(kind, x, y)
+}
+
+fn make_monster(kind: MonsterKind, x: i32, y: i32) -> Object {
+    let mut new_monster = match kind {
+        MonsterKind::Orc => {
+            let mut orc = Object::new(x, y, 'o', "orc", COLOR_ORC, false);
+            orc.fighter = Some(Fighter { max_hp: 10, hp: 10, accuracy: 0, evasion: 0, damage: Dice::new(1, 4, 1),
+                                         damage_type: DamageType::Physical, armour: 0, resistances: Resistances::none(),
+                                         worn: None, on_death: DeathCallback::Monster, attack_effect: None });
+            orc.ai = Some(Ai::new(8, false));
+            orc
+        }
+        MonsterKind::Bat => {
+            // Bats are fast and act twice per turn.
+            let mut bat = Object::new(x, y, 'b', "bat", COLOR_BAT, false);
+            bat.fighter = Some(Fighter { max_hp: 4, hp: 4, accuracy: 1, evasion: 4, damage: Dice::new(1, 3, 0),
+                                         damage_type: DamageType::Physical, armour: 0, resistances: Resistances::none(),
+                                         worn: None, on_death: DeathCallback::Monster, attack_effect: None });
+            // They find their way in the dark, but do not see far.
+            bat.ai = Some(Ai::new(5, true));
+            bat.speed = 2 * NORMAL_SPEED;
+            bat
+        }
+        MonsterKind::Zombie => {
+            // Zombies are slow and only act every other turn.
+            let mut zombie = Object::new(x, y, 'Z', "zombie", COLOR_ZOMBIE, false);
+            // Their bite is poisonous, and being dead already, poison does not hurt them.
+            let resistances = Resistances::none().with(DamageType::Poison, Affinity::Immune);
+            zombie.fighter = Some(Fighter { max_hp: 20, hp: 20, accuracy: -2, evasion: -2, damage: Dice::new(1, 6, 1),
+                                            damage_type: DamageType::Physical, armour: 0, resistances, worn: None,
+                                            on_death: DeathCallback::Monster,
+                                            attack_effect: Some(Effect { kind: EffectKind::Poison, turns: 5 }) });
+            zombie.ai = Some(Ai::new(4, true));
+            zombie.speed = NORMAL_SPEED / 2;
+            zombie
+        }
+        MonsterKind::Troll => {
+            let mut troll = Object::new(x, y, 'T', "troll", COLOR_TROLL, false);
+            troll.fighter = Some(Fighter { max_hp: 16, hp: 16, accuracy: 1, evasion: 0, damage: Dice::new(1, 6, 2),
+                                           damage_type: DamageType::Physical, armour: 1, resistances: Resistances::none(),
+                                           worn: None, on_death: DeathCallback::Monster, attack_effect: None });
+            troll.ai = Some(Ai::new(7, false));
+            troll
+        }
+        MonsterKind::FireImp => {
+            // Fire imps burn what they hit, glow in the dark and fear the cold.
+            let mut imp = Object::new(x, y, 'i', "fire imp", COLOR_FIRE_IMP, false);
+            let resistances = Resistances::none()
+                .with(DamageType::Fire, Affinity::Immune)
+                .with(DamageType::Cold, Affinity::Vulnerable);
+            imp.fighter = Some(Fighter { max_hp: 8, hp: 8, accuracy: 2, evasion: 3, damage: Dice::new(1, 6, 0),
+                                         damage_type: DamageType::Fire, armour: 0, resistances, worn: None,
+                                         on_death: DeathCallback::Monster, attack_effect: None });
+            imp.ai = Some(Ai::new(7, true));
+            imp.light = Some(Light { radius: 3, color: COLOR_FIRE_LIGHT, falloff: 1.5 });
+            imp
+        }
+        MonsterKind::IceGolem => {
+            // Ice golems are slow, hard to hurt, and melt in fire. Their freezing touch slows down
+            // whoever they hit.
+            let mut golem = Object::new(x, y, 'G', "ice golem", COLOR_ICE_GOLEM, false);
+            let resistances = Resistances::none()
+                .with(DamageType::Cold, Affinity::Immune)
+                .with(DamageType::Poison, Affinity::Immune)
+                .with(DamageType::Physical, Affinity::Resistant)
+                .with(DamageType::Fire, Affinity::Vulnerable);
+            golem.fighter = Some(Fighter { max_hp: 24, hp: 24, accuracy: 0, evasion: -2, damage: Dice::new(1, 8, 1),
+                                           damage_type: DamageType::Cold, armour: 1, resistances, worn: None,
+                                           on_death: DeathCallback::Monster,
+                                           attack_effect: Some(Effect { kind: EffectKind::Slow, turns: 5 }) });
+            golem.ai = Some(Ai::new(6, false));
+            golem.speed = NORMAL_SPEED / 2;
+            golem
+        }
+        MonsterKind::OrcWarlord => {
+            let mut warlord = Object::new(x, y, 'O', "orc warlord", COLOR_ORC_WARLORD, false);
+            warlord.fighter = Some(Fighter { max_hp: 40, hp: 40, accuracy: 3, evasion: 1, damage: Dice::new(2, 6, 2),
+                                             damage_type: DamageType::Physical, armour: 2,
+                                             resistances: Resistances::none(), worn: None,
+                                             on_death: DeathCallback::Monster, attack_effect: None });
+            warlord.ai = Some(Ai::new(10, false));
+            warlord
+        }
+    };
+    if let Some(ai) = new_monster.ai.as_mut() {
+        if rng().gen::<f32>() < ASLEEP_CHANCE {
+            ai.alertness = Alertness::Asleep;
+        }
+    }
+    new_monster.loot = kind.loot();
+    new_monster
+}
+
+/// Create a random item at the given position.
+fn random_item(x: i32, y: i32) -> Object {
+    // 43% healing potion, 10% potion of speed, 7% potion of regeneration, 8% scroll of
+    // confusion, 5% scroll of fireball, 5% scroll of lightning bolt, 4% short bow, 2% crossbow,
+    // 8% arrows, 4% bolts, 2% leather armour, 2% salamander scale armour
+    let roll = rng().gen::<f32>();
+    let item = if roll < 0.6 {
+        random_potion(roll / 0.6)
+    } else if roll < 0.68 {
+        Item::Confuse
+    } else if roll < 0.73 {
+        Item::Fireball
+    } else if roll < 0.78 {
+        Item::Lightning
+    } else if roll < 0.82 {
+        SHORT_BOW
+    } else if roll < 0.84 {
+        CROSSBOW
+    } else if roll < 0.92 {
+        Item::Ammo(AmmoKind::Arrow)
+    } else if roll < 0.96 {
+        Item::Ammo(AmmoKind::Bolt)
+    } else if roll < 0.98 {
+        Item::Armour(ArmourKind::Leather)
+    } else {
+        Item::Armour(ArmourKind::Salamander)
+    };
+    make_item(item, x, y)
+}
+
+/// Pick a potion given a roll between 0 and 1.
+fn random_potion(roll: f32) -> Item {
+    // 71% healing potion, 17% potion of speed, 12% potion of regeneration
+    if roll < 0.71 {
+        Item::Heal
+    } else if roll < 0.88 {
+        Item::Haste
+    } else {
+        Item::Regenerate
+    }
+}
+
+fn make_item(item: Item, x: i32, y: i32) -> Object {
+    let mut object = match item {
+        Item::Heal => Object::new(x, y, '!', "healing potion", colors::VIOLET, true),
+        Item::Haste => Object::new(x, y, '!', "potion of speed", colors::LIGHT_BLUE, true),
+        Item::Regenerate => Object::new(x, y, '!', "potion of regeneration", colors::LIGHT_RED, true),
+        Item::Confuse => Object::new(x, y, '#', "scroll of confusion", colors::LIGHT_YELLOW, true),
+        Item::Fireball => Object::new(x, y, '#', "scroll of fireball", colors::LIGHT_YELLOW, true),
+        Item::Lightning => Object::new(x, y, '#', "scroll of lightning bolt", colors::LIGHT_YELLOW, true),
+        Item::Launcher(Launcher { ammo: AmmoKind::Arrow, .. }) =>
+            Object::new(x, y, '}', "short bow", colors::SEPIA, true),
+        Item::Launcher(Launcher { ammo: AmmoKind::Bolt, .. }) =>
+            Object::new(x, y, '}', "crossbow", colors::DARK_SEPIA, true),
+        Item::Ammo(AmmoKind::Arrow) => {
+            let mut arrows = Object::new(x, y, '/', "arrow", colors::LIGHT_SEPIA, true);
+            arrows.count = rng().gen_range(5, 16);
+            arrows
+        }
+        Item::Ammo(AmmoKind::Bolt) => {
+            let mut bolts = Object::new(x, y, '/', "bolt", colors::LIGHT_GREY, true);
+            bolts.count = rng().gen_range(5, 11);
+            bolts
+        }
+        Item::Key => Object::new(x, y, '-', "iron key", colors::GOLD, true),
+        Item::Armour(ArmourKind::Leather) => Object::new(x, y, '[', ArmourKind::Leather.name(), colors::SEPIA, true),
+        Item::Armour(ArmourKind::Salamander) =>
+            Object::new(x, y, '[', ArmourKind::Salamander.name(), colors::DARK_FLAME, true),
+        Item::Gold => Object::new(x, y, '$', "gold piece", colors::GOLD, true),
+    };
+    object.item = Some(item);
+    object
+}
+
+/// Stamp a random vault into the given area, where there is enough solid rock for it.
+/// Returns the area the vault takes and its entrances (see `stamp_vault`), if it fit.
+fn place_vault(area: &Rect, map: &mut Map, objects: &mut Vec<Object>) -> Option<(Rect, Vec<(i32, i32)>)> {
+    let vault = LOADED_VAULTS.with(|vaults| vaults[rng().gen_range(0, vaults.len())].random_orientation());
+    let (w, h) = (vault.width(), vault.height());
+
+    // Keep the vault and the walls around it inside the area, and away from the edges of the map.
+    let (min_x, min_y) = (max(area.x1, 0) + 2, max(area.y1, 0) + 2);
+    let (max_x, max_y) = (min(area.x2, MAP_WIDTH - 1) - w, min(area.y2, MAP_HEIGHT - 1) - h);
+    if min_x >= max_x || min_y >= max_y {
+        return None;
+    }
+    for _ in 0..VAULT_PLACEMENT_TRIES {
+        let x = rng().gen_range(min_x, max_x);
+        let y = rng().gen_range(min_y, max_y);
+        let solid = ((x - 1)..(x + w + 1)).all(|x| {
+            ((y - 1)..(y + h + 1)).all(|y| !map[(x, y)].is_passable())
+        });
+        if solid {
+            let entrances = stamp_vault(&vault, x, y, map, objects);
+            return Some((Rect::new(x, y, w - 1, h - 1), entrances));
+        }
+    }
+    None
+}
+
+/// Dig tunnels from the entrances of a vault taking the given area to the rest of the map.
+fn connect_vault(area: &Rect, entrances: &[(i32, i32)], map: &mut Map) {
+    for &entrance in entrances {
+        dig_to_floor(entrance, area, map);
+    }
+}
+
+/// Copy a vault into the map with its top left corner at the given position, and spawn its
+/// objects. Returns the tiles just outside its outer doors, which lead to the rest of the map.
+fn stamp_vault(vault: &Vault, x: i32, y: i32, map: &mut Map, objects: &mut Vec<Object>) -> Vec<(i32, i32)> {
+    let mut entrances = vec![];
+
+    for vault_x in 0..vault.width() {
+        for vault_y in 0..vault.height() {
+            let (map_x, map_y) = (x + vault_x, y + vault_y);
+            let tile = &mut map[(map_x, map_y)];
+            match vault.at(vault_x, vault_y) {
+                ' ' => continue,
+                '#' => *tile = Tile::wall(),
+                '+' => {
+                    *tile = Tile::door(DoorState::Closed);
+                    // Doors on the edge lead outside.
+                    let outside = if vault_x == 0 {
+                        Some((map_x - 1, map_y))
+                    } else if vault_x == vault.width() - 1 {
+                        Some((map_x + 1, map_y))
+                    } else if vault_y == 0 {
+                        Some((map_x, map_y - 1))
+                    } else if vault_y == vault.height() - 1 {
+                        Some((map_x, map_y + 1))
+                    } else {
+                        None
+                    };
+                    entrances.extend(outside);
+                }
+                _ => *tile = Tile::empty(),
+            }
+        }
+    }
+
+    // Objects are only spawned once all the floor is in place.
+    for vault_x in 0..vault.width() {
+        for vault_y in 0..vault.height() {
+            let (map_x, map_y) = (x + vault_x, y + vault_y);
+            match vault.at(vault_x, vault_y) {
+                'o' => spawn(make_monster(MonsterKind::Orc, map_x, map_y), map, objects),
+                'M' => spawn(random_monster(map_x, map_y), map, objects),
+                '!' => spawn(make_item(random_potion(rng().gen()), map_x, map_y), map, objects),
+                '?' => spawn(random_item(map_x, map_y), map, objects),
+                // A level only has one staircase down.
+                '>' if !objects.iter().any(|o| o.is_stairs()) => spawn(make_stairs(map_x, map_y), map, objects),
+                _ => {}
+            }
+        }
+    }
+
+    entrances
+}
+
+/// Dig the shortest tunnel from the given position to the closest floor tile, going around the
+/// given area.
+fn dig_to_floor(start: (i32, i32), area: &Rect, map: &mut Map) {
+    let inside = |(x, y): (i32, i32)| x >= area.x1 && x <= area.x2 && y >= area.y1 && y <= area.y2;
+    let mut came_from = HashMap::new();
+    let mut queue = VecDeque::new();
+    came_from.insert(start, start);
+    queue.push_back(start);
+
+    while let Some(current) = queue.pop_front() {
+        if map[current].is_passable() {
+            // Found some floor: dig back to the start.
+            let mut position = current;
+            while position != start {
+                position = came_from[&position];
+                map[position] = Tile::empty();
+            }
+            return;
+        }
+        for &(dx, dy) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let next = (current.0 + dx, current.1 + dy);
+            if next.0 <= 0 || next.1 <= 0 || next.0 >= MAP_WIDTH - 1 || next.1 >= MAP_HEIGHT - 1 {
+                continue;
+            }
+            if !inside(next) && !came_from.contains_key(&next) {
+                came_from.insert(next, current);
+                queue.push_back(next);
+            }
         }
-    } else {
-        None
     }
 }
 
-fn inventory_menu(inventory: &[Object], header: &str, root: &mut Root) -> Option<usize> {
-    // how a menu with each item of the inventory as an option
-    let options = if inventory.len() == 0 {
-        vec!["Inventory is empty.".into()]
-    } else {
-        inventory.iter().map(|item| { item.name.clone() }).collect()
-    };
-
-    let inventory_index = menu(header, &options, INVENTORY_WIDTH, root);
+thread_local! {
+    static LOADED_VAULTS: Vec<Vault> = load_vaults(VAULT_DIR);
+}
 
-    // if an item was chosen, return it
-    if inventory.len() > 0 {
-        inventory_index
+/// Read the vault templates from the `*.txt` files in the given directory. The files are read
+/// in name order, so that a seed generates the same levels for the same templates. Falls back
+/// on the built-in `VAULTS` if the directory is missing or holds no templates.
+fn load_vaults(dir: &str) -> Vec<Vault> {
+    let mut paths: Vec<_> = std::fs::read_dir(dir).into_iter().flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().map_or(false, |extension| extension == "txt"))
+        .collect();
+    paths.sort();
+    let vaults: Vec<Vault> = paths.iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .map(|template| Vault::parse(&template))
+        .filter(|vault| vault.height() > 0)
+        .collect();
+    if vaults.is_empty() {
+        VAULTS.iter().map(|template| Vault::parse(template)).collect()
     } else {
-        None
+        vaults
     }
 }
 
-fn render_messages(messages: &Messages, panel: &mut Offscreen) {
-    let mut y = MSG_HEIGHT as i32;
-    for &(ref msg, color) in messages.iter().rev() {
-        let msg_height = panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, msg);
-        y -= msg_height;
-        if y < 0 {
-            break;
-        }
-        panel.set_default_foreground(color);
-        panel.print_rect(MSG_X, y, MSG_WIDTH, 0, msg);
-    }
+/// A handcrafted room, as a grid of legend characters (see `VAULTS`).
+#[derive(Clone, Debug)]
+struct Vault {
+    rows: Vec<Vec<char>>,
 }
 
+impl Vault {
+    pub fn parse(template: &str) -> Self {
+        let mut rows: Vec<Vec<char>> = template.lines()
+            .map(|line| line.trim_end_matches('\r').chars().collect())
+            .filter(|row: &Vec<char>| !row.is_empty())
+            .collect();
+        // Pad the rows so that the vault is a rectangle.
+        let width = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+        for row in &mut rows {
+            row.resize(width, ' ');
+        }
+        Vault { rows }
+    }
 
-fn make_map(objects: &mut Vec<Object>) -> (Map, (i32, i32)) {
-    // Fill map with untraversable tiles
-    // vec![ITEM;NUM] is a macro to create a Vec of size NUM filled with ITEM (where ITEM is
-    // evaluated at each iteration).
-    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    pub fn width(&self) -> i32 {
+        self.rows.first().map_or(0, |row| row.len() as i32)
+    }
 
-    let mut starting_position = (0, 0);
-    let mut rooms: Vec<Rect> = Vec::new();
+    pub fn height(&self) -> i32 {
+        self.rows.len() as i32
+    }
 
-    for _ in 0..MAX_ROOMS {
-        // Random width / height
-        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+    pub fn at(&self, x: i32, y: i32) -> char {
+        self.rows[y as usize][x as usize]
+    }
 
-        // random position without going out of the boundaries of the map.
-        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
-        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
+    /// The vault turned a quarter clockwise.
+    pub fn rotated(&self) -> Self {
+        let rows = (0..self.width())
+            .map(|x| (0..self.height()).rev().map(|y| self.at(x, y)).collect())
+            .collect();
+        Vault { rows }
+    }
 
-        let new_room = Rect::new(x, y, w, h);
+    /// The vault flipped left to right.
+    pub fn mirrored(&self) -> Self {
+        let rows = self.rows.iter().map(|row| row.iter().rev().cloned().collect()).collect();
+        Vault { rows }
+    }
 
-        // If this room intersects with another one, it is considered invalid.
-        let invalid = rooms.iter().any(|other_room| new_room.intersects_with(other_room));
-        if invalid {
-            continue;
+    /// The vault in any of its eight orientations.
+    pub fn random_orientation(&self) -> Self {
+        let mut vault = self.clone();
+        for _ in 0..rng().gen_range(0, 4) {
+            vault = vault.rotated();
         }
+        if rng().gen() {
+            vault = vault.mirrored();
+        }
+        vault
+    }
+}
 
-        create_room(new_room, &mut map);
-        let (new_x, new_y) = new_room.center();
+// Map generation tool
 
-        if rooms.is_empty() {
-            // Player starts in first room, no tunnel needed.
-            starting_position = (new_x, new_y);
-        } else {
-            // Place objets (monsters, items, ...).
-            place_objects(&new_room, &map, objects);
+const MAPGEN_USAGE: &str = "usage: rust-roguelike mapgen [--seed N] [--depth N] [--style rooms|bsp|caves] [--batch N]";
 
-            // All other rooms should be connected with the previous one.
-            let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
+/// Generate maps without opening a window, to tune the generators. A single map is printed as
+/// ASCII with its statistics, while batch mode only prints statistics over consecutive seeds.
+fn mapgen(args: &[String]) {
+    let mut seed: Option<u64> = None;
+    let mut depth = 1;
+    let mut style = None;
+    let mut batch: Option<u64> = None;
 
-            // We randomly use tunnel_x or tunnel_y first.
-            if rand::random() {
-                create_h_tunnel(prev_x, new_x, prev_y, &mut map);
-                create_v_tunnel(prev_y, new_y, new_x, &mut map);
-            } else {
-                // first move vertically, then horizontally
-                create_v_tunnel(prev_y, new_y, prev_x, &mut map);
-                create_h_tunnel(prev_x, new_x, new_y, &mut map);
-            }
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().map(String::as_str).unwrap_or("");
+        let valid = match arg.as_str() {
+            "--seed" => value.parse().map(|v| seed = Some(v)).is_ok(),
+            "--depth" => value.parse().ok().filter(|&v| v >= 1).map(|v| depth = v).is_some(),
+            "--style" => MapStyle::from_name(value).map(|v| style = Some(v)).is_some(),
+            "--batch" => value.parse().map(|v| batch = Some(v)).is_ok(),
+            _ => false,
+        };
+        if !valid {
+            eprintln!("{}", MAPGEN_USAGE);
+            std::process::exit(1);
         }
+    }
+    let style = style.unwrap_or_else(|| MapStyle::for_level(depth));
 
-        rooms.push(new_room);
+    match batch {
+        None => {
+            let seed = seed.unwrap_or_else(|| rng().gen());
+            seed_rng(seed);
+            let (map, objects, start) = generate_level(style, depth);
+
+            print!("{}", map_to_ascii(&map, &objects, start));
+            println!("seed: {}, depth: {}, style: {:?}", seed, depth, style);
+            for (name, value) in MapStats::new(&map, &objects).values() {
+                println!("{}: {:.1}", name, value);
+            }
+        }
+        Some(count) => {
+            let first_seed = seed.unwrap_or(0);
+            let mut stats = vec![];
+            for seed in first_seed..(first_seed + count) {
+                seed_rng(seed);
+                let (map, objects, _) = generate_level(style, depth);
+                stats.push(MapStats::new(&map, &objects));
+            }
 
+            println!("seeds: {}..{}, depth: {}, style: {:?}", first_seed, first_seed + count, depth, style);
+            println!("{:<16}{:>10}{:>10}{:>10}", "", "mean", "min", "max");
+            if let Some(first) = stats.first() {
+                for (i, (name, _)) in first.values().into_iter().enumerate() {
+                    let values: Vec<f32> = stats.iter().map(|s| s.values()[i].1).collect();
+                    let mean = values.iter().sum::<f32>() / values.len() as f32;
+                    let min = values.iter().cloned().fold(std::f32::INFINITY, f32::min);
+                    let max = values.iter().cloned().fold(std::f32::NEG_INFINITY, f32::max);
+                    println!("{:<16}{:>10.1}{:>10.1}{:>10.1}", name, mean, min, max);
+                }
+            }
+        }
     }
+}
 
-    (map, starting_position)
+/// Draw a map as text: objects over tiles, with the player's starting position as `@`.
+fn map_to_ascii(map: &Map, objects: &[Object], start: (i32, i32)) -> String {
+    let mut text = String::new();
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            let tile = &map[(x, y)];
+            // Blocking objects are drawn over the other ones.
+            let object = objects.iter().filter(|o| o.pos() == (x, y)).min_by_key(|o| o.traversable);
+            let glyph = if (x, y) == start {
+                '@'
+            } else if let Some(object) = object {
+                object.char
+            } else if tile.trap.is_some() {
+                '^'
+            } else if tile.door == Some(DoorState::Secret) {
+                'S'
+            } else if tile.is_wall() {
+                '#'
+            } else {
+                tile.glyph().unwrap_or('.')
+            };
+            text.push(glyph);
+        }
+        text.push('\n');
+    }
+    text
+}
 
+/// Numbers describing a generated map.
+struct MapStats {
+    /// Open areas, where all the tiles around a floor tile are floor too. Tunnels are not rooms.
+    rooms: usize,
+    /// Percentage of the map which can be walked on.
+    floor_coverage: f32,
+    /// Floor tiles with a single orthogonal way out.
+    dead_ends: usize,
+    doors: usize,
+    traps: usize,
+    monsters: usize,
+    items: usize,
 }
 
-fn create_room(rect: Rect, map: &mut Map) {
-    for x in (rect.x1 + 1)..rect.x2 {
-        for y in (rect.y1 + 1)..rect.y2 {
-            map[x as usize][y as usize] = Tile::empty();
+impl MapStats {
+    pub fn new(map: &Map, objects: &[Object]) -> Self {
+        let passable = |(x, y): (i32, i32)| map.get(x, y).map_or(false, |tile| tile.is_passable());
+        let neighbours = |x: i32, y: i32| map.neighbours(x, y).filter(|&position| passable(position)).count();
+        let orthogonal_neighbours =
+            |x: i32, y: i32| map.orthogonal_neighbours(x, y).filter(|&position| passable(position)).count();
+
+        let mut floor = 0;
+        let mut dead_ends = 0;
+        let mut open = Grid::new(MAP_WIDTH, MAP_HEIGHT, false);
+        for y in 0..MAP_HEIGHT {
+            for x in 0..MAP_WIDTH {
+                if !passable((x, y)) {
+                    continue;
+                }
+                floor += 1;
+                if orthogonal_neighbours(x, y) == 1 {
+                    dead_ends += 1;
+                }
+                if neighbours(x, y) == 8 {
+                    open[(x, y)] = true;
+                }
+            }
+        }
+
+        // Count the connected open areas.
+        let mut rooms = 0;
+        for y in 0..MAP_HEIGHT {
+            for x in 0..MAP_WIDTH {
+                if !open[(x, y)] {
+                    continue;
+                }
+                rooms += 1;
+                let mut stack = vec![(x, y)];
+                open[(x, y)] = false;
+                while let Some((x, y)) = stack.pop() {
+                    for next in map.orthogonal_neighbours(x, y) {
+                        if passable(next) && open[next] {
+                            open[next] = false;
+                            stack.push(next);
+                        }
+                    }
+                }
+            }
+        }
+
+        MapStats {
+            rooms,
+            floor_coverage: 100.0 * floor as f32 / (MAP_WIDTH * MAP_HEIGHT) as f32,
+            dead_ends,
+            doors: map.iter().filter(|(_, tile)| tile.door.is_some()).count(),
+            traps: map.iter().filter(|(_, tile)| tile.trap.is_some()).count(),
+            monsters: objects.iter().filter(|o| o.ai.is_some()).count(),
+            items: objects.iter().filter(|o| o.item.is_some()).count(),
         }
     }
-}
 
-fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
-    for x in min(x1, x2)..(max(x1, x2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
+    pub fn values(&self) -> Vec<(&'static str, f32)> {
+        vec![
+            ("rooms", self.rooms as f32),
+            ("floor coverage", self.floor_coverage),
+            ("dead ends", self.dead_ends as f32),
+            ("doors", self.doors as f32),
+            ("traps", self.traps as f32),
+            ("monsters", self.monsters as f32),
+            ("items", self.items as f32),
+        ]
     }
 }
 
-fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
-    for y in min(y1, y2)..(max(y1, y2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
+// Benchmark
+
+const BENCH_USAGE: &str = "usage: rust-roguelike bench [--seed N] [--density PERCENT] [--turns N]";
+
+/// Time entity lookups and monster turns on an open level, with a monster or an item on the
+/// given percentage of its tiles. All the monsters head for the player, so that every turn is
+/// full of path finding.
+fn bench(args: &[String]) {
+    let mut seed = 0;
+    let mut density = 30;
+    let mut turns = 10;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().map(String::as_str).unwrap_or("");
+        let valid = match arg.as_str() {
+            "--seed" => value.parse().map(|v| seed = v).is_ok(),
+            "--density" => value.parse().map(|v| density = v).is_ok(),
+            "--turns" => value.parse().map(|v| turns = v).is_ok(),
+            _ => false,
+        };
+        if !valid {
+            eprintln!("{}", BENCH_USAGE);
+            std::process::exit(1);
+        }
     }
-}
+    seed_rng(seed);
 
-/// Create objects (monsters, items) in a given room.
-fn place_objects(room: &Rect, map: &Map, objects: &mut Vec<Object>) {
-    let num_monsters = rand::thread_rng().gen_range(0, MAX_ROOM_MONSTERS + 1);
-    let Rect { x1, y1, x2, y2 } = *room;
+    // A single room as large as the map.
+    let mut map = Grid::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
+    create_room(Rect::new(0, 0, MAP_WIDTH - 1, MAP_HEIGHT - 1), &mut map);
 
-    for _ in 0..num_monsters {
-        let x = rand::thread_rng().gen_range(x1 + 1, x2);
-        let y = rand::thread_rng().gen_range(y1 + 1, y2);
-
-        if is_traversable(x, y, map, objects) {
-            // 80% chance orc, 20% troll
-            let mut new_monster = if rand::random::<f32>() < 0.8 {
-                let mut orc = Object::new(x, y, 'o', "orc", COLOR_ORC, false);
-                orc.fighter = Some(Fighter { max_hp: 10, hp: 10, defence: 0, power: 3, on_death: DeathCallback::Monster});
-                orc.ai = Some(Ai);
-                orc
+    let mut world = World::new();
+    let mut player = Object::new(MAP_WIDTH / 2, MAP_HEIGHT / 2, '@', "player", colors::WHITE, false);
+    player.energy = ENERGY_TO_ACT;
+    // The player survives the whole benchmark.
+    player.fighter = Some(Fighter { max_hp: 1000000, hp: 1000000, accuracy: 2, evasion: 2, damage: Dice::new(1, 4, 3),
+                                    damage_type: DamageType::Physical, armour: 2, resistances: Resistances::none(),
+                                    worn: None, on_death: DeathCallback::Player, attack_effect: None });
+    player.light = Some(PLAYER_TORCH);
+    world.spawn(player);
+    for y in 1..(MAP_HEIGHT - 1) {
+        for x in 1..(MAP_WIDTH - 1) {
+            if (x, y) == world.pos(PLAYER_ID) || rng().gen_range(0, 100) >= density {
+                continue;
+            }
+            if rng().gen() {
+                let mut monster = random_monster(x, y);
+                if let Some(ai) = monster.ai.as_mut() {
+                    ai.alertness = Alertness::Investigating;
+                    ai.destination = Some((MAP_WIDTH / 2, MAP_HEIGHT / 2));
+                }
+                world.spawn(monster);
             } else {
-                let mut troll = Object::new(x, y, 'T', "troll", COLOR_TROLL, false);
-                troll.fighter = Some(Fighter { max_hp: 16, hp: 16, defence: 1, power: 4, on_death: DeathCallback::Monster});
-                troll.ai = Some(Ai);
-                troll
-            };
-            new_monster.alive = true;
-            objects.push(new_monster);
+                world.spawn(random_item(x, y));
+            }
         }
     }
-    let num_items = rand::thread_rng().gen_range(0, MAX_ROOM_ITEMS + 1);
-    for _ in 0..num_items {
-        let x = rand::thread_rng().gen_range(x1 + 1, x2);
-        let y = rand::thread_rng().gen_range(y1 + 1, y2);
+    println!("map: {}x{}, entities: {}, monsters: {}, items: {}", MAP_WIDTH, MAP_HEIGHT,
+             world.entities().len(), world.ais.iter().count(), world.items.iter().count());
 
-        if is_traversable(x, y, map, objects) {
-            let mut object = Object::new(x, y, '!', "healing potion", colors::VIOLET, true);
-            object.item = Some(Item::Heal);
-            objects.push(object);
+    let start = Instant::now();
+    let mut found = 0;
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            found += world.blocking_at(x, y).iter().count() + world.items_at(x, y).count();
         }
     }
+    let lookups = 2 * MAP_WIDTH * MAP_HEIGHT;
+    println!("lookups: {} ({} found), {:.0} ns per lookup", lookups, found,
+             start.elapsed().as_secs_f64() * 1e9 / lookups as f64);
+
+    let mut fov_map = FovMap::new(MAP_WIDTH, MAP_HEIGHT);
+    update_fov_map(&map, &mut fov_map);
+    let mut messages = Messages::new();
+    let start = Instant::now();
+    for _ in 0..turns {
+        world.actors[PLAYER_ID].energy -= WAIT_COST;
+        play_until_player_turn(&mut map, &mut world, &mut messages, &fov_map);
+    }
+    println!("turns: {}, {:.2} ms per turn", turns, start.elapsed().as_secs_f64() * 1e3 / turns as f64);
 }
 
 // Movement
-fn is_traversable(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
-    // Could be blocked by a tile...
-    if ! map[x as usize][y as usize].traversable {
+fn is_traversable(x: i32, y: i32, map: &Map, world: &World) -> bool {
+    // Could be blocked by a tile, or be off the map...
+    if !map.get(x, y).map_or(false, |tile| tile.traversable) {
         return false;
     }
-    // ...or by an object.
-    ! objects.iter().any(|o| {
-        ! o.traversable && o.pos() == (x, y)
-    })
+    // ...or by an entity.
+    world.blocking_at(x, y).is_none()
 }
 
 fn render_bar(
@@ -766,23 +5027,98 @@ fn render_bar(
     );
 }
 
+/// Draw the explored part of the map at a smaller scale, with the player, the known stairs and
+/// the items seen on the way.
+fn render_minimap(minimap: &mut Offscreen, map: &Map, world: &World) {
+    minimap.set_default_background(colors::BLACK);
+    minimap.clear();
+
+    for cell_y in 0..MINIMAP_HEIGHT {
+        for cell_x in 0..MINIMAP_WIDTH {
+            // A cell is floor if any of its explored tiles is floor.
+            let mut explored = false;
+            let mut floor = false;
+            for y in (cell_y * MINIMAP_SCALE)..min(MAP_HEIGHT, (cell_y + 1) * MINIMAP_SCALE) {
+                for x in (cell_x * MINIMAP_SCALE)..min(MAP_WIDTH, (cell_x + 1) * MINIMAP_SCALE) {
+                    let tile = &map[(x, y)];
+                    explored |= tile.explored;
+                    floor |= tile.explored && !tile.is_wall();
+                }
+            }
+            if explored {
+                let color = if floor { COLOR_LIGHT_GROUND } else { COLOR_DARK_WALL };
+                minimap.set_char_background(cell_x, cell_y, color, BackgroundFlag::Set);
+            }
+        }
+    }
+
+    // Items first, so that the stairs and the player are drawn over them. Items are shown as
+    // they were the last time their tile was seen, even if they were picked up since.
+    for ((x, y), tile) in map.iter() {
+        if let Some(item) = tile.seen_item {
+            minimap.set_default_foreground(item.color);
+            minimap.put_char(x / MINIMAP_SCALE, y / MINIMAP_SCALE, item.char, BackgroundFlag::None);
+        }
+    }
+    let stairs = world.entities().into_iter().filter(|&id| {
+        let (x, y) = world.pos(id);
+        world.is_stairs(id) && map[(x, y)].explored
+    });
+    for id in stairs.chain(Some(PLAYER_ID)) {
+        let (x, y) = world.pos(id);
+        let renderable = world.renderables[id];
+        minimap.set_default_foreground(renderable.color);
+        minimap.put_char(x / MINIMAP_SCALE, y / MINIMAP_SCALE, renderable.char, BackgroundFlag::None);
+    }
+}
+
+/// Render short labels for the given status effects, wrapping them within BAR_WIDTH.
+fn render_effects(panel: &mut Offscreen, x: i32, y: i32, effects: &[Effect]) {
+    let (mut label_x, mut label_y) = (x, y);
+    for effect in effects {
+        let label = effect.kind.label();
+        if label_x + label.len() as i32 > x + BAR_WIDTH {
+            label_x = x;
+            label_y += 1;
+        }
+        panel.set_default_foreground(effect.kind.color());
+        panel.print_ex(label_x, label_y, BackgroundFlag::None, TextAlignment::Left, label);
+        label_x += label.len() as i32 + 1;
+    }
+}
+
 fn log_message<T: Into<String>>(messages: &mut Messages, message: T, color: Color) {
-    // If the buffer is full, make way.
-    if messages.len() == MSG_HEIGHT {
-        messages.remove(0);
+    let text = message.into();
+
+    // Collapse consecutive identical messages into a single one with a count.
+    if let Some(last) = messages.history.back_mut() {
+        if last.text == text && last.color == color {
+            last.count += 1;
+            return;
+        }
+    }
+
+    // If the history is full, make way.
+    if messages.history.len() == MSG_HISTORY_SIZE {
+        messages.history.pop_front();
     }
-    messages.push((message.into(), color));
+    messages.history.push_back(Message { text, color, turn: messages.turn, count: 1 });
 }
 
-/// return a string with the names of all objects under the mouse
-fn get_names_under_mouse(mouse: Mouse, objects: &[Object], fov_map: &FovMap) -> String {
-    let (x, y) = (mouse.cx as i32, mouse.cy as i32);
+/// return a string with the names of all entities under the mouse
+fn get_names_under_mouse(mouse: Mouse, world: &World, fov_map: &FovMap, camera: &Camera) -> String {
+    let (x, y) = match camera.to_map(mouse.cx as i32, mouse.cy as i32) {
+        Some(position) => position,
+        None => return String::new(),
+    };
 
-    // Create a list with the names of all objects at the mouse's coordinates and in FOV.
-    let names = objects
+    // Create a list with the names of all entities at the mouse's coordinates and in FOV.
+    if !fov_map.is_in_fov(x, y) {
+        return String::new();
+    }
+    let names = world.entities_at(x, y)
         .iter()
-        .filter(|obj| {obj.pos() == (x, y) && fov_map.is_in_fov(obj.x, obj.y)})
-        .map(|obj| obj.name.clone())
+        .map(|&id| world.names[id].clone())
         .collect::<Vec<_>>();
 
     names.join(", ")  // Join the names, separated by commas.
@@ -793,17 +5129,25 @@ fn get_names_under_mouse(mouse: Mouse, objects: &[Object], fov_map: &FovMap) ->
 /// # Return value
 ///
 /// A value of true means that the caller should exit.
-fn handle_keys(key: Key, root: &mut Root, map: &Map,
-               objects: &mut Vec<Object>, inventory: &mut Vec<Object>,
-               messages: &mut Messages) -> PlayerAction {
+fn handle_keys(key: Key, screen: &mut Screen, map: &mut Map,
+               world: &mut World, inventory: &mut Vec<Object>,
+               messages: &mut Messages, fov_map: &FovMap) -> PlayerAction {
 
     use self::PlayerAction::*;
 
-    let player_alive = objects[PLAYER_ID].alive;
+    let Screen { root, camera, show_minimap, .. } = screen;
+
+    let player_alive = world.is_alive(PLAYER_ID);
 
     let mut do_move_by = |dx: i32, dy: i32| {
-        move_by_or_attack(PLAYER_ID, dx, dy, map, objects, messages);
-        TookTurn
+        let (dx, dy) = stumble(PLAYER_ID, dx, dy, world);
+        let (player_x, player_y) = world.pos(PLAYER_ID);
+        let (x, y) = (player_x + dx, player_y + dy);
+        if map.get(x, y).map_or(false, |tile| tile.door == Some(DoorState::Locked)) {
+            unlock_door(x, y, map, world, inventory, messages)
+        } else {
+            TookTurn(move_by_or_attack(PLAYER_ID, dx, dy, map, world, messages))
+        }
     };
 
     match (key, player_alive) {
@@ -819,21 +5163,86 @@ fn handle_keys(key: Key, root: &mut Root, map: &Map,
 
         (Key {code: Char, printable: 'g', .. }, true) => {
             // pick an item
-            let item_id = objects.iter().position(
-                |object| object.pos() == objects[PLAYER_ID].pos() && object.item.is_some());
-            if let Some(item_id) = item_id {
-                pick_item_up(item_id, objects, inventory, messages);
+            let player_pos = world.pos(PLAYER_ID);
+            let item_id = world.items_at(player_pos.0, player_pos.1).next();
+            match item_id {
+                Some(item_id) if pick_item_up(item_id, world, inventory, messages) => TookTurn(PICK_UP_COST),
+                _ => DidntTakeTurn,
+            }
+        }
+
+        (Key { code: Char, printable: 'f', .. }, true) => {
+            // fire a launcher
+            fire(root, map, world, inventory, messages, fov_map, camera)
+        }
+
+        (Key { code: Char, printable: 't', .. }, true) => {
+            // throw an item
+            throw(root, map, world, inventory, messages, fov_map, camera)
+        }
+
+        (Key { code: Char, printable: 'c', .. }, true) => {
+            // close a door
+            close_door(root, map, world, messages)
+        }
+
+        (Key { code: Char, printable: 's', .. }, true) => {
+            // search for secret doors
+            search(map, world, messages)
+        }
+
+        (Key { code: Char, printable: 'T', .. }, true) => {
+            // put out or light the torch
+            toggle_torch(world, messages)
+        }
+
+        (Key { printable: '>', .. }, true) => {
+            // go down the stairs
+            let player_pos = world.pos(PLAYER_ID);
+            if world.entities_at(player_pos.0, player_pos.1).iter().any(|&id| world.is_stairs(id)) {
+                Descend
+            } else {
+                log_message(messages, "There are no stairs here.", colors::WHITE);
+                DidntTakeTurn
+            }
+        }
+
+        (Key { code: Char, printable: 'x', .. }, true) => {
+            // look at something
+            if let Some((x, y)) = target_tile(root, world, fov_map, camera, SIGHT_RADIUS) {
+                msgbox(&describe_tile(x, y, world), CHARACTER_SCREEN_WIDTH, root);
             }
             DidntTakeTurn
         }
 
+        (Key { code: Char, printable: 'C', .. }, true) => {
+            // show the character screen
+            msgbox(&character_screen(world), CHARACTER_SCREEN_WIDTH, root);
+            DidntTakeTurn
+        }
+
+        (Key { code: Char, printable: 'm', .. }, _) => {
+            // show the message history
+            message_history(messages, root);
+            DidntTakeTurn
+        }
+
+        (Key { code: Tab, .. }, _) => {
+            // show or hide the minimap
+            *show_minimap = !*show_minimap;
+            DidntTakeTurn
+        }
+
         (Key { printable: 'i', .. }, true) => {
-            // show the inventory
-            inventory_menu(
+            // show the inventory, and use the chosen item
+            let inventory_index = inventory_menu(
                 inventory,
                 "Press the key next to an item to use it, or any other to cancel.\n",
                 root);
-            TookTurn
+            match inventory_index.map(|index| use_item(index, inventory, map, world, messages, fov_map)) {
+                Some(UseResult::UsedUp) => TookTurn(USE_ITEM_COST),
+                _ => DidntTakeTurn,
+            }
         }
 
         // Alt-enter: toggle fullscreen
@@ -849,3 +5258,385 @@ fn handle_keys(key: Key, root: &mut Root, map: &Map,
         _ => DidntTakeTurn,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEEDS: u64 = 500;
+
+    /// Generate levels the way the game does, over two boss intervals of depths, so that bosses
+    /// are placed and rejected maps are generated again.
+    fn assert_valid_maps(style: MapStyle) {
+        for seed in 0..SEEDS {
+            seed_rng(seed);
+            let depth = 1 + (seed % (2 * BOSS_LEVEL_INTERVAL as u64)) as u32;
+            let (map, objects, start) = generate_level(style, depth);
+            if let Err(error) = validate_map(&map, &objects, start) {
+                panic!("seed {}: invalid {:?} map: {}", seed, style, error);
+            }
+            if depth.is_multiple_of(BOSS_LEVEL_INTERVAL) {
+                let stairs = objects.iter().find(|o| o.is_stairs()).expect("No stairs").pos();
+                assert!(objects.iter().any(|o| o.name == "orc warlord" && o.pos() == stairs),
+                        "seed {}: no boss on the stairs of a {:?} map", seed, style);
+            }
+        }
+    }
+
+    #[test]
+    fn rooms_maps_are_valid() {
+        assert_valid_maps(MapStyle::Rooms);
+    }
+
+    #[test]
+    fn bsp_maps_are_valid() {
+        assert_valid_maps(MapStyle::Bsp { extra_loops: BSP_EXTRA_LOOPS });
+    }
+
+    #[test]
+    fn cave_maps_are_valid() {
+        assert_valid_maps(MapStyle::Caves);
+    }
+
+    #[test]
+    fn repeated_messages_are_collapsed() {
+        let mut messages = Messages::new();
+        log_message(&mut messages, "The orc attacks you.", colors::WHITE);
+        log_message(&mut messages, "The orc attacks you.", colors::WHITE);
+        log_message(&mut messages, "The bat attacks you.", colors::WHITE);
+        log_message(&mut messages, "The orc attacks you.", colors::WHITE);
+        log_message(&mut messages, "The orc attacks you.", colors::WHITE);
+        log_message(&mut messages, "The orc attacks you.", colors::WHITE);
+
+        let texts: Vec<String> = messages.history.iter().map(Message::display_text).collect();
+        assert_eq!(texts, ["The orc attacks you. x2", "The bat attacks you.", "The orc attacks you. x3"]);
+    }
+
+    #[test]
+    fn message_history_is_capped() {
+        let mut messages = Messages::new();
+        for i in 0..(MSG_HISTORY_SIZE + 10) {
+            log_message(&mut messages, format!("Message {}", i), colors::WHITE);
+        }
+        assert_eq!(messages.history.len(), MSG_HISTORY_SIZE);
+        assert_eq!(messages.history.front().unwrap().text, "Message 10");
+        assert_eq!(messages.history.back().unwrap().text, format!("Message {}", MSG_HISTORY_SIZE + 9));
+    }
+
+    /// A world holding a single orc, in the middle of an empty map.
+    fn orc_world() -> (Map, World, Entity) {
+        let map = Grid::new(MAP_WIDTH, MAP_HEIGHT, Tile::empty());
+        let mut world = World::new();
+        let orc = world.spawn(make_monster(MonsterKind::Orc, 10, 10));
+        (map, world, orc)
+    }
+
+    #[test]
+    fn reapplied_effects_last_for_the_longest_duration() {
+        let (_, mut world, orc) = orc_world();
+        let mut messages = Messages::new();
+        apply_effect(orc, EffectKind::Poison, 3, &mut world, &mut messages);
+        apply_effect(orc, EffectKind::Poison, 5, &mut world, &mut messages);
+        apply_effect(orc, EffectKind::Poison, 2, &mut world, &mut messages);
+
+        assert_eq!(world.effects[orc], [Effect { kind: EffectKind::Poison, turns: 5 }]);
+        assert_eq!(messages.history.len(), 1);
+    }
+
+    #[test]
+    fn effects_expire_after_their_duration() {
+        let (map, mut world, orc) = orc_world();
+        let mut messages = Messages::new();
+        apply_effect(orc, EffectKind::Haste, 2, &mut world, &mut messages);
+        apply_effect(orc, EffectKind::Poison, 3, &mut world, &mut messages);
+
+        tick_effects(&map, &mut world, &mut messages);
+        assert!(world.has_effect(orc, EffectKind::Haste));
+        tick_effects(&map, &mut world, &mut messages);
+        assert!(!world.has_effect(orc, EffectKind::Haste));
+        assert!(world.has_effect(orc, EffectKind::Poison));
+        assert_eq!(messages.history.back().unwrap().text, "orc is no longer hasted.");
+        tick_effects(&map, &mut world, &mut messages);
+        assert!(world.effects[orc].is_empty());
+
+        // Poison hurt on each of the three turns it lasted.
+        assert_eq!(world.fighters[orc].hp, world.fighters[orc].max_hp - 3 * POISON_DAMAGE);
+    }
+
+    fn vault_rows(vault: &Vault) -> Vec<String> {
+        vault.rows.iter().map(|row| row.iter().collect()).collect()
+    }
+
+    #[test]
+    fn vaults_are_parsed_into_rectangles() {
+        let vault = Vault::parse("###\r\n\n#.+##\n#>\n");
+        assert_eq!((vault.width(), vault.height()), (5, 3));
+        assert_eq!(vault_rows(&vault), vec!["###  ", "#.+##", "#>   "]);
+    }
+
+    #[test]
+    fn vaults_rotate_clockwise() {
+        let vault = Vault::parse("ab\ncd\nef");
+        let rotated = vault.rotated();
+        assert_eq!((rotated.width(), rotated.height()), (3, 2));
+        assert_eq!(vault_rows(&rotated), vec!["eca", "fdb"]);
+        let full_turn = rotated.rotated().rotated().rotated();
+        assert_eq!(vault_rows(&full_turn), vault_rows(&vault));
+    }
+
+    #[test]
+    fn vaults_mirror_left_to_right() {
+        let vault = Vault::parse("ab.\n#+>");
+        assert_eq!(vault_rows(&vault.mirrored()), vec![".ba", ">+#"]);
+        assert_eq!(vault_rows(&vault.mirrored().mirrored()), vault_rows(&vault));
+    }
+
+    #[test]
+    fn vaults_are_loaded_from_their_directory() {
+        let loaded = load_vaults(VAULT_DIR);
+        let files = std::fs::read_dir(VAULT_DIR).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension().map_or(false, |extension| extension == "txt"))
+            .count();
+        assert_eq!(loaded.len(), files);
+
+        let fallback = load_vaults("no such directory");
+        assert_eq!(fallback.len(), VAULTS.len());
+        assert_eq!(vault_rows(&fallback[0]), vault_rows(&Vault::parse(VAULTS[0])));
+    }
+
+    #[test]
+    fn stale_handles_do_not_reach_entities_reusing_their_slot() {
+        let (_, mut world, orc) = orc_world();
+        assert!(world.despawn(orc).is_some());
+        assert!(world.fighters.get(orc).is_none());
+        assert!(world.despawn(orc).is_none());
+
+        let bat = world.spawn(make_monster(MonsterKind::Bat, 5, 5));
+        assert_eq!(bat.index, orc.index);
+        assert_ne!(bat, orc);
+        assert!(!world.contains(orc));
+        assert!(world.names.get(orc).is_none());
+        assert!(world.fighters.get(orc).is_none());
+        assert!(world.contains(bat));
+    }
+
+    #[test]
+    fn freed_slots_are_reused_last_freed_first() {
+        let (_, mut world, first) = orc_world();
+        let second = world.spawn(make_monster(MonsterKind::Orc, 11, 10));
+        let third = world.spawn(make_monster(MonsterKind::Orc, 12, 10));
+        world.despawn(first);
+        world.despawn(third);
+        world.despawn(second);
+
+        let reused: Vec<usize> = (0..3).map(|x| world.spawn(make_monster(MonsterKind::Orc, x, 12)).index).collect();
+        assert_eq!(reused, [second.index, third.index, first.index]);
+        let fresh = world.spawn(make_monster(MonsterKind::Orc, 3, 12));
+        assert_eq!(fresh.index, world.slots.len() - 1);
+    }
+
+    #[test]
+    fn the_same_seed_generates_the_same_level() {
+        let generate = |seed: u64, depth: u32| {
+            seed_rng(seed);
+            let mut world = World::new();
+            world.spawn(Object::new(0, 0, '@', "player", colors::WHITE, false));
+            let (map, start) = make_map(&mut world, depth);
+            let objects: Vec<(String, (i32, i32))> = world.entities().into_iter()
+                .map(|id| (world.names[id].clone(), world.pos(id)))
+                .collect();
+            (map_to_ascii(&map, &[], start), objects)
+        };
+        for depth in 1..=BOSS_LEVEL_INTERVAL {
+            for &seed in &[7, 1234] {
+                assert_eq!(generate(seed, depth), generate(seed, depth), "seed {}, depth {}", seed, depth);
+            }
+            assert_ne!(generate(7, depth), generate(1234, depth));
+        }
+    }
+
+    #[test]
+    fn grid_positions_outside_the_grid_have_no_value() {
+        let mut grid = Grid::new(3, 2, 0);
+        *grid.get_mut(2, 1).unwrap() = 5;
+        assert_eq!(grid.get(2, 1), Some(&5));
+        assert_eq!(grid[(2, 1)], 5);
+        for &(x, y) in &[(-1, 0), (0, -1), (-1, -1), (3, 0), (0, 2), (3, 2), (i32::MIN, 0), (0, i32::MAX)] {
+            assert_eq!(grid.get(x, y), None, "({}, {})", x, y);
+            assert!(grid.get_mut(x, y).is_none(), "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn grid_neighbours_stay_inside_the_grid() {
+        let grid = Grid::new(3, 2, 0);
+        let mut corner: Vec<_> = grid.neighbours(0, 0).collect();
+        corner.sort();
+        assert_eq!(corner, [(0, 1), (1, 0), (1, 1)]);
+        let mut edge: Vec<_> = grid.orthogonal_neighbours(1, 1).collect();
+        edge.sort();
+        assert_eq!(edge, [(0, 1), (1, 0), (2, 1)]);
+    }
+
+    #[test]
+    fn projectiles_report_their_attacks_as_events() {
+        let (map, mut world, orc) = orc_world();
+        let archer = world.spawn(make_monster(MonsterKind::Orc, 5, 10));
+        let mut messages = Messages::new();
+        fly_projectile(archer, "arrow", (10, 10), Dice::new(1, 4, 0), &map, &mut world, &mut messages);
+
+        let attacks: Vec<&GameEvent> = world.events.replay.events.iter()
+            .map(|(_, event)| event)
+            .filter(|event| matches!(event, GameEvent::Attacked { .. }))
+            .collect();
+        assert_eq!(attacks.len(), 1);
+        match attacks[0] {
+            GameEvent::Attacked { attacker, target, projectile, .. } => {
+                assert_eq!((*attacker, *target), (archer, orc));
+                assert_eq!(projectile.as_deref(), Some("arrow"));
+            }
+            _ => unreachable!(),
+        }
+        assert!(messages.history.iter().any(|message| message.display_text().starts_with("The arrow ")));
+    }
+
+    #[test]
+    fn dice_rolls_stay_within_their_range() {
+        seed_rng(48);
+        let dice = Dice::new(2, 6, 1);
+        let rolls: Vec<i32> = (0..1000).map(|_| dice.roll()).collect();
+        assert!(rolls.iter().all(|&roll| (3..=13).contains(&roll)));
+        assert_eq!((rolls.iter().min(), rolls.iter().max()), (Some(&3), Some(&13)));
+        assert_eq!(Dice::new(0, 0, -2).roll(), -2);
+    }
+
+    #[test]
+    fn dice_are_displayed_with_their_bonus() {
+        assert_eq!(Dice::new(2, 6, 1).to_string(), "2d6+1");
+        assert_eq!(Dice::new(1, 4, -2).to_string(), "1d4-2");
+        assert_eq!(Dice::new(1, 8, 0).to_string(), "1d8");
+    }
+
+    #[test]
+    fn armour_never_stops_a_hit_entirely() {
+        seed_rng(48);
+        assert_eq!(roll_damage(Dice::new(1, 4, 0), 100, HitRoll::Hit), MIN_DAMAGE);
+        assert_eq!(roll_damage(Dice::new(1, 4, 0), 100, HitRoll::Critical), MIN_DAMAGE);
+        assert_eq!(roll_damage(Dice::new(1, 4, 0), 0, HitRoll::Miss), 0);
+        // Something too light to hurt deals no damage at all.
+        assert_eq!(roll_damage(Dice::new(0, 0, 0), 0, HitRoll::Hit), 0);
+    }
+
+    #[test]
+    fn critical_hits_roll_the_damage_twice() {
+        let dice = Dice::new(3, 6, 2);
+        for seed in 0..100 {
+            seed_rng(seed);
+            let twice = dice.roll() + dice.roll();
+            seed_rng(seed);
+            assert_eq!(roll_damage(dice, 1, HitRoll::Critical), twice - 1, "seed {}", seed);
+        }
+        assert_eq!(roll_damage(Dice::new(1, 1, 0), 0, HitRoll::Hit), 1);
+        assert_eq!(roll_damage(Dice::new(1, 1, 0), 0, HitRoll::Critical), 2);
+    }
+
+    #[test]
+    fn attacks_report_the_damage_left_after_affinities() {
+        let (map, mut world, orc) = orc_world();
+        let mut messages = Messages::new();
+        let attacker = world.spawn(make_monster(MonsterKind::Orc, 11, 10));
+        world.fighters[attacker].accuracy = 100;
+        world.fighters[attacker].damage = Dice::new(0, 0, 20);
+        let defender = &mut world.fighters[orc];
+        defender.armour = 0;
+        defender.hp = 100;
+        defender.resistances = Resistances::none().with(DamageType::Physical, Affinity::Resistant);
+        seed_rng(49);
+        attack(attacker, orc, &map, &mut world, &mut messages);
+
+        let reported = world.events.replay.events.iter().find_map(|(_, event)| match event {
+            GameEvent::Attacked { hit: HitRoll::Hit, damage, .. } => Some((*damage, 10)),
+            GameEvent::Attacked { hit: HitRoll::Critical, damage, .. } => Some((*damage, 20)),
+            _ => None,
+        });
+        let (damage, expected) = reported.expect("The attack missed");
+        assert_eq!(damage, expected);
+        assert_eq!(world.fighters[orc].hp, 100 - damage);
+    }
+
+    const TEST_LOOT: LootTable = LootTable {
+        drop_chance: 1.0,
+        entries: &[
+            LootEntry { item: Item::Heal, weight: 3, min_level: 1 },
+            LootEntry { item: Item::Confuse, weight: 1, min_level: 1 },
+            LootEntry { item: Item::Fireball, weight: 4, min_level: 3 },
+        ],
+        gold_chance: 0.0,
+        gold: Dice { count: 0, sides: 0, bonus: 2 },
+        guaranteed: &[],
+    };
+
+    /// How many of the given item were dropped over the given number of rolls.
+    fn loot_count(table: &LootTable, level: u32, rolls: u32, item: Item) -> i32 {
+        seed_rng(50);
+        (0..rolls).flat_map(|_| table.roll(level))
+            .filter(|drop| drop.item == Some(item))
+            .map(|drop| drop.count)
+            .sum()
+    }
+
+    #[test]
+    fn loot_is_picked_by_weight_from_the_entries_of_the_level() {
+        let heal = loot_count(&TEST_LOOT, 1, 4000, Item::Heal);
+        let confuse = loot_count(&TEST_LOOT, 1, 4000, Item::Confuse);
+        assert_eq!(heal + confuse, 4000);
+        assert!((2850..3150).contains(&heal), "{} potions out of 4000", heal);
+        assert_eq!(loot_count(&TEST_LOOT, 2, 4000, Item::Fireball), 0);
+
+        let fireballs = loot_count(&TEST_LOOT, 3, 4000, Item::Fireball);
+        assert!((1850..2150).contains(&fireballs), "{} fireballs out of 4000", fireballs);
+    }
+
+    #[test]
+    fn loot_chance_and_gold_grow_with_depth() {
+        let table = LootTable { drop_chance: 0.25, gold_chance: 1.0, ..TEST_LOOT };
+        let drops = |level: u32| {
+            let items: i32 = [Item::Heal, Item::Confuse, Item::Fireball].iter()
+                .map(|&item| loot_count(&table, level, 4000, item))
+                .sum();
+            (items, loot_count(&table, level, 4000, Item::Gold))
+        };
+        let (first, first_gold) = drops(1);
+        assert!((850..1150).contains(&first), "{} drops out of 4000", first);
+        assert_eq!(first_gold, 2 * 4000);
+
+        let (deep, deep_gold) = drops(11);
+        let expected = (4000.0 * (0.25 + 10.0 * LOOT_CHANCE_PER_LEVEL)) as i32;
+        assert!((expected - 150..expected + 150).contains(&deep), "{} drops out of 4000", deep);
+        assert_eq!(deep_gold, 2 * 11 * 4000);
+
+        // Items can be rolled outside of the dungeon too, with the chance of the first level.
+        assert_eq!(LootTable { drop_chance: 0.0, gold_chance: 0.0, ..TEST_LOOT }.roll(0).len(), 0);
+    }
+
+    #[test]
+    fn bosses_always_drop_their_treasure() {
+        let (map, mut world, _) = orc_world();
+        let mut messages = Messages::new();
+        let treasure = MonsterKind::OrcWarlord.loot().expect("No boss loot").guaranteed;
+        assert!(!treasure.is_empty());
+        for seed in 0..20 {
+            seed_rng(seed);
+            let boss = world.spawn(make_monster(MonsterKind::OrcWarlord, 20, 20));
+            drop_loot(boss, &map, &mut world, &mut messages);
+            let dropped: Vec<Item> = world.items.iter().map(|(_, &item)| item).collect();
+            for item in treasure {
+                assert!(dropped.contains(item), "seed {}: no {:?} dropped", seed, item);
+            }
+            for id in world.items.ids() {
+                world.despawn(id);
+            }
+            world.despawn(boss);
+        }
+    }
+}